@@ -1,10 +1,16 @@
+use std::num::IntErrorKind;
+
 use crate::opcode::OpCode;
 
 #[derive(Copy, Clone)]
 pub enum TokenType {
     OpCode(OpCode),
     Register(u8),
-    Integer(u16),
+    FloatRegister(u8),
+    Integer(i64),
+    Float(f64),
+    // The `.byte` directive, followed by the values of raw bytes to insert in the code
+    Byte,
 }
 
 pub struct Token {
@@ -19,6 +25,8 @@ pub struct Lexer {
     current: usize,
     line: usize,
     column: usize,
+    // Column of the first character of the current token
+    start_column: usize,
     pub tokens: Vec<Token>,
 }
 
@@ -30,15 +38,14 @@ impl Lexer {
             current: 0,
             line: 1,
             column: 1,
+            start_column: 1,
             tokens: Vec::new(),
         }
     }
 
     pub fn scan_tokens(&mut self) -> Result<(), String> {
         while !self.is_at_end() {
-            if let Err(e) = self.next_token() {
-                return Err(e);
-            }
+            self.next_token()?;
         }
 
         Ok(())
@@ -52,67 +59,125 @@ impl Lexer {
         }
 
         self.start = self.current;
+        self.start_column = self.column;
 
         let c = self.advance();
 
         match c {
-            '!' => Ok(self.comment()),
-            'a'..='z' | 'A'..='Z' => Ok(self.opcode()),
-            '%' => Ok(self.register()),
-            '#' => Ok(self.integer()),
-            _ => Err(format!("Unexpected character: {}", c)),
+            '!' => self.comment(),
+            'a'..='z' | 'A'..='Z' => self.opcode(),
+            '%' => {
+                let register = self.register_index()?;
+                self.add_token(TokenType::Register(register));
+            }
+            '$' => {
+                let register = self.register_index()?;
+                self.add_token(TokenType::FloatRegister(register));
+            }
+            '.' => self.directive()?,
+            '#' => self.integer()?,
+            _ => return Err(format!("Unexpected character: {}", c)),
         }
+
+        Ok(())
     }
 
     fn opcode(&mut self) {
-        while self.peek().is_alphabetic() {
+        while self.peek().is_alphanumeric() {
             self.advance();
         }
 
         let text = &self.input[self.start..self.current];
-        let opcode = match text.to_lowercase().as_str() {
-            "stop" => OpCode::STOP,
-            "load" => OpCode::LOAD,
-            "add" => OpCode::ADD,
-            "sub" => OpCode::SUB,
-            "mul" => OpCode::MUL,
-            "div" => OpCode::DIV,
-            "jmp" => OpCode::JMP,
-            "jfw" => OpCode::JFW,
-            "jbk" => OpCode::JBK,
-            "eq" => OpCode::EQ,
-            "neq" => OpCode::NEQ,
-            "gt" => OpCode::GT,
-            "lt" => OpCode::LT,
-            "gte" => OpCode::GTE,
-            "lte" => OpCode::LTE,
-            "jeq" => OpCode::JEQ,
-            "jne" => OpCode::JNE,
-            "sys" => OpCode::SYS,
-            _ => OpCode::UKWN,
-        };
+        let opcode = OpCode::from(text.to_lowercase().as_str());
 
         self.add_token(TokenType::OpCode(opcode));
     }
 
-    fn register(&mut self) {
-        let mut value = 0;
-        while self.peek().is_digit(10) {
-            let digit = self.advance().to_digit(10).unwrap();
-            value = value * 10 + digit as u16;
+    fn directive(&mut self) -> Result<(), String> {
+        while self.peek().is_alphanumeric() {
+            self.advance();
+        }
+
+        match &self.input[self.start..self.current] {
+            ".byte" => self.add_token(TokenType::Byte),
+            directive => {
+                return Err(format!(
+                    "Unknown directive {} at {}:{}",
+                    directive, self.line, self.start_column
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    // The index following a `%` or `$` register prefix, shared by both register banks
+    fn register_index(&mut self) -> Result<u8, String> {
+        while self.peek().is_ascii_digit() {
+            self.advance();
+        }
+
+        // Skip the prefix
+        let text = &self.input[self.start + 1..self.current];
+        if text.is_empty() {
+            return Ok(0);
         }
 
-        self.add_token(TokenType::Register(value as u8));
+        text.parse::<u8>().map_err(|_| {
+            format!(
+                "Register {} out of range 0..=255 at {}:{}",
+                text, self.line, self.start_column
+            )
+        })
     }
 
-    fn integer(&mut self) {
-        let mut value = 0;
-        while self.peek().is_digit(10) {
-            let digit = self.advance().to_digit(10).unwrap();
-            value = value * 10 + digit as u16;
+    fn integer(&mut self) -> Result<(), String> {
+        if self.peek() == '-' {
+            self.advance();
+        }
+
+        while self.peek().is_ascii_digit() {
+            self.advance();
         }
 
+        if self.peek() == '.' {
+            return self.float();
+        }
+
+        // Skip the leading '#'
+        let text = &self.input[self.start + 1..self.current];
+        let value = text.parse::<i64>().map_err(|e| match e.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => format!(
+                "Integer literal out of range at {}:{}",
+                self.line, self.start_column
+            ),
+            _ => format!(
+                "Invalid integer literal {} at {}:{}",
+                text, self.line, self.start_column
+            ),
+        })?;
+
         self.add_token(TokenType::Integer(value));
+        Ok(())
+    }
+
+    // Continues an integer literal followed by a fractional part, e.g. #-1.5
+    fn float(&mut self) -> Result<(), String> {
+        self.advance();
+        while self.peek().is_ascii_digit() {
+            self.advance();
+        }
+
+        let text = &self.input[self.start + 1..self.current];
+        let value = text.parse::<f64>().map_err(|_| {
+            format!(
+                "Invalid float literal {} at {}:{}",
+                text, self.line, self.start_column
+            )
+        })?;
+
+        self.add_token(TokenType::Float(value));
+        Ok(())
     }
 
     fn comment(&mut self) {
@@ -125,7 +190,7 @@ impl Lexer {
         let token = Token {
             token_type,
             line: self.line,
-            column: self.column,
+            column: self.start_column,
         };
 
         self.tokens.push(token);
@@ -138,9 +203,9 @@ impl Lexer {
                     self.advance();
                 }
                 '\n' => {
+                    self.advance();
                     self.line += 1;
                     self.column = 1;
-                    self.advance();
                 }
                 _ => break,
             }
@@ -3,49 +3,283 @@ use crate::vm::VM;
 mod lexer;
 mod parser;
 
-pub fn assemble(input: String, vm: VM) -> Result<VM, String> {
+pub fn assemble<H>(input: String, vm: VM<H>) -> Result<VM<H>, String> {
+    assemble_file("", input, vm)
+}
+
+// Like `assemble`, but names the source file in the VM's line table
+pub fn assemble_file<H>(file: &str, input: String, vm: VM<H>) -> Result<VM<H>, String> {
     let mut lexer = lexer::Lexer::new(input);
 
-    if let Err(e) = lexer.scan_tokens() {
-        return Err(e);
-    }
+    lexer.scan_tokens()?;
 
-    let mut parser = parser::Parser::new(lexer.tokens, vm);
+    let parser = parser::Parser::new(file, lexer.tokens, vm);
 
-    Ok(parser.parse())
+    parser.parse()
 }
 
 #[cfg(test)]
 mod assembler_tests {
     use super::*;
+    use crate::opcode::OpCode;
 
     #[test]
     fn test_assemble() {
         let input = String::from("load %0 #123\nload %1 #456\nadd %2 %0 %1\n");
         let mut vm = assemble(input, VM::new()).unwrap();
 
-        vm.run();
+        vm.run().unwrap();
 
         assert_eq!(vm.registers[0], 123);
         assert_eq!(vm.registers[1], 456);
         assert_eq!(vm.registers[2], 579);
     }
 
+    #[test]
+    fn test_line_table() {
+        let input =
+            String::from("load %0 #1\n! Divide by zero\n\nload %1 #0\n  div %2 %0 %1\n.byte #0\n");
+        let mut vm = assemble_file("divide.rm", input, VM::new()).unwrap();
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(
+            vm.describe_error(&error),
+            "division by zero at divide.rm:5:3"
+        );
+        assert_eq!(vm.source_location(3).unwrap().to_string(), "divide.rm:1:1");
+        assert_eq!(vm.source_location(12).unwrap().to_string(), "divide.rm:6:1");
+        assert_eq!(vm.source_location(13), None);
+
+        // Code assembled into the same VM is added to its line table
+        vm = assemble(String::from("stop\n"), vm).unwrap();
+        assert_eq!(vm.source_location(13).unwrap().to_string(), "1:1");
+    }
+
     #[test]
     fn test_append_code() {
         let input = String::from("load %0 #123\nload %1 #456\nadd %2 %0 %1\n");
         let mut vm = assemble(input, VM::new()).unwrap();
 
-        vm.run();
+        vm.run().unwrap();
 
         let input2 = String::from("load %3 #579\neq %3 %2\n");
         vm = assemble(input2, vm).unwrap();
 
-        vm.run();
+        vm.run().unwrap();
 
         assert_eq!(vm.registers[0], 123);
         assert_eq!(vm.registers[1], 456);
         assert_eq!(vm.registers[3], 579);
-        assert_eq!(vm.comparison, true);
+        assert!(vm.comparison);
+    }
+
+    #[test]
+    fn test_memory_instructions() {
+        let input = String::from("load %0 #8\nload %1 #513\nst16 %0 %1\nld8 %2 %0\n");
+        let mut vm = assemble(input, VM::new()).unwrap();
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.read_memory(8, 2), Some(&[2, 1][..]));
+        assert_eq!(vm.registers[2], 2);
+    }
+
+    #[test]
+    fn test_bitwise_instructions() {
+        let input = String::from(
+            "load %0 #12\nload %1 #10\nload %2 #2\nand %3 %0 %1\nor %4 %0 %1\nxor %5 %0 %1\nnot %6 %0\nshl %7 %0 %2\nshr %8 %0 %2\nsar %9 %6 %2\n",
+        );
+        let mut vm = assemble(input, VM::new()).unwrap();
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[3], 8);
+        assert_eq!(vm.registers[4], 14);
+        assert_eq!(vm.registers[5], 6);
+        assert_eq!(vm.registers[6], -13);
+        assert_eq!(vm.registers[7], 48);
+        assert_eq!(vm.registers[8], 3);
+        assert_eq!(vm.registers[9], -4);
+    }
+
+    #[test]
+    fn test_math_instructions() {
+        let input = String::from(
+            "load %0 #17\nload %1 #5\nmod %2 %0 %1\nneg %3 %0\nabs %4 %3\nmin %5 %0 %1\nmax %6 %0 %1\ninc %0\ndec %1\n",
+        );
+        let mut vm = assemble(input, VM::new()).unwrap();
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], 18);
+        assert_eq!(vm.registers[1], 4);
+        assert_eq!(vm.registers[2], 2);
+        assert_eq!(vm.registers[3], -17);
+        assert_eq!(vm.registers[4], 17);
+        assert_eq!(vm.registers[5], 5);
+        assert_eq!(vm.registers[6], 17);
+    }
+
+    #[test]
+    fn test_checked_multiply() {
+        let input = String::from("load %0 #6\nload %1 #7\nmult %2 %0 %1\n");
+        let mut vm = assemble(input, VM::new()).unwrap();
+
+        assert_eq!(vm.code[8], OpCode::MULT as u8);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 42);
+    }
+
+    #[test]
+    fn test_immediate_instructions() {
+        // Counts %0 up to 10 without loading any constants into registers
+        let input = String::from("load %1 #4\naddi %0 %0 #1\nlti %0 #10\njeq %1\nmuli %2 %0 #3\n");
+        let mut vm = assemble(input, VM::new()).unwrap();
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], 10);
+        assert_eq!(vm.registers[2], 30);
+    }
+
+    #[test]
+    fn test_wide_load() {
+        let input = String::from("load %0 #65535\nload %1 #65536\nload %2 #-1\nload %3 #-9223372036854775808\nloadw %4 #5\n");
+        let mut vm = assemble(input, VM::new()).unwrap();
+
+        // Only values outside of 0..=65535 use the wide encoding, unless it is asked for explicitly
+        assert_eq!(vm.code[0], OpCode::LOAD as u8);
+        assert_eq!(vm.code[4], OpCode::LOADW as u8);
+        assert_eq!(vm.code.len(), 4 + 10 + 10 + 10 + 10);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], 65535);
+        assert_eq!(vm.registers[1], 65536);
+        assert_eq!(vm.registers[2], -1);
+        assert_eq!(vm.registers[3], i64::MIN);
+        assert_eq!(vm.registers[4], 5);
+    }
+
+    #[test]
+    fn test_signed_immediates() {
+        let input = String::from(
+            "addi %1 %0 #-1
+subi %2 %0 #-32768
+addi %3 %0 #32767
+lti %1 #-1
+",
+        );
+        let mut vm = assemble(input, VM::new()).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[1], -1);
+        assert_eq!(vm.registers[2], 32768);
+        assert_eq!(vm.registers[3], 32767);
+        assert!(!vm.comparison);
+    }
+
+    #[test]
+    fn test_integer_out_of_range() {
+        let input = String::from("load %0 #9223372036854775808\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from("Integer literal out of range at 1:9"))
+        );
+
+        let input = String::from("load %0 #1\naddi %0 %0 #70000\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from(
+                "Integer 70000 out of range -32768..=32767 at 2:12"
+            ))
+        );
+
+        let input = String::from(
+            "lti %0 #-32769
+",
+        );
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from(
+                "Integer -32769 out of range -32768..=32767 at 1:8"
+            ))
+        );
+
+        let input = String::from("sys #-1\n");
+        assert!(assemble(input, VM::new()).is_err());
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let input = String::from("inc %255\nfmov $255 $0\n");
+        assert!(assemble(input, VM::new()).is_ok());
+
+        let input = String::from("inc %256\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from("Register 256 out of range 0..=255 at 1:5"))
+        );
+
+        let input = String::from("fmov $0 $256\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from("Register 256 out of range 0..=255 at 1:9"))
+        );
+
+        // Long enough to overflow any integer type
+        let input = String::from("inc %300000000000000000000\n");
+        assert!(assemble(input, VM::new()).is_err());
+    }
+
+    #[test]
+    fn test_float_instructions() {
+        let input = String::from(
+            "fload $0 #-1.25\nfload $1 #2\nfmul $2 $0 $1\nload %1 #3\nitof $3 %1\nflt $2 $3\nftoi %2 $2\n",
+        );
+        let mut vm = assemble(input, VM::new()).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.float_registers[0], -1.25);
+        assert_eq!(vm.float_registers[1], 2.0);
+        assert_eq!(vm.float_registers[2], -2.5);
+        assert_eq!(vm.float_registers[3], 3.0);
+        assert_eq!(vm.registers[2], -2);
+        assert!(vm.comparison);
+
+        let input = String::from("load %0 #1.5\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from("Expected integer at 1:9"))
+        );
+    }
+
+    #[test]
+    fn test_operand_errors() {
+        let input = String::from("add %0 %1 $2\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from("Expected register at 1:11"))
+        );
+
+        let input = String::from("load %0 #1\nmov %1\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from(
+                "Missing register operand for the instruction at 2:1"
+            ))
+        );
+
+        let input = String::from("load %0 #1\nfoo %1\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from("Unknown instruction at 2:1"))
+        );
+
+        let input = String::from("%1 %2\n");
+        assert_eq!(
+            assemble(input, VM::new()).err(),
+            Some(String::from("Expected an instruction at 1:1"))
+        );
     }
 }
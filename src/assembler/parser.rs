@@ -1,43 +1,160 @@
 use super::lexer::{Token, TokenType};
+use crate::instruction::{Instruction, Operand, OperandKind};
+use crate::opcode::OpCode;
 use crate::vm::VM;
 
-pub struct Parser {
+pub struct Parser<H> {
+    // Name of the source file, recorded in the line table
+    file: String,
     tokens: Vec<Token>,
     current: usize,
-    vm: VM,
+    vm: VM<H>,
 }
 
-impl Parser {
-    pub fn new(tokens: Vec<Token>, vm: VM) -> Self {
+impl<H> Parser<H> {
+    pub fn new(file: &str, tokens: Vec<Token>, vm: VM<H>) -> Self {
         Self {
+            file: file.to_string(),
             tokens,
             current: 0,
             vm,
         }
     }
 
-    pub fn parse(&mut self) -> VM {
+    pub fn parse(mut self) -> Result<VM<H>, String> {
         while !self.is_at_end() {
-            self.next_instruction();
+            let token = &self.tokens[self.current];
+            let (line, column) = (token.line, token.column);
+            let start = self.vm.code.len();
+
+            if let TokenType::Byte = token.token_type {
+                self.bytes()?;
+            } else {
+                let instruction = self.next_instruction()?;
+                self.vm.write_instruction(instruction);
+            }
+
+            let end = self.vm.code.len();
+            self.vm.line_table.add(&self.file, start..end, line, column);
         }
 
-        self.vm.clone()
+        Ok(self.vm)
+    }
+
+    fn next_instruction(&mut self) -> Result<Instruction, String> {
+        let token = self.advance();
+
+        let (line, column) = (token.line, token.column);
+        let opcode = match token.token_type {
+            TokenType::OpCode(OpCode::UKWN) => {
+                return Err(format!("Unknown instruction at {}:{}", line, column))
+            }
+            TokenType::OpCode(opcode) => opcode,
+            _ => return Err(format!("Expected an instruction at {}:{}", line, column)),
+        };
+
+        // `load` only fits 16-bit unsigned values, anything else needs the wide encoding
+        let opcode = match opcode {
+            OpCode::LOAD if !self.loaded_value_fits_u16() => OpCode::LOADW,
+            opcode => opcode,
+        };
+
+        let operands = opcode
+            .operand_kinds()
+            .iter()
+            .map(|kind| self.operand(*kind, line, column))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Instruction::from_operands(opcode, &operands)
+            .expect("operands are parsed from the opcode's operand kinds"))
     }
 
-    fn next_instruction(&mut self) {
+    // Writes the values following a `.byte` directive to the code as is
+    fn bytes(&mut self) -> Result<(), String> {
         let token = self.advance();
+        let (line, column) = (token.line, token.column);
+
+        let mut count = 0;
+        while let Some(&Token {
+            token_type: TokenType::Integer(value),
+            line,
+            column,
+        }) = self.tokens.get(self.current)
+        {
+            let byte = u8::try_from(value).map_err(|_| {
+                format!("Byte {} out of range 0..=255 at {}:{}", value, line, column)
+            })?;
+            self.vm.write_u8(byte);
+            self.current += 1;
+            count += 1;
+        }
+
+        if count == 0 {
+            return Err(format!(
+                "Expected integer after .byte at {}:{}",
+                line, column
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Parses the next token as an operand of the given kind
+    // `line` and `column` locate the instruction, for errors at the end of the input
+    fn operand(
+        &mut self,
+        kind: OperandKind,
+        line: usize,
+        column: usize,
+    ) -> Result<Operand, String> {
+        if self.is_at_end() {
+            return Err(format!(
+                "Missing {} operand for the instruction at {}:{}",
+                kind, line, column
+            ));
+        }
 
-        let token_type = token.token_type;
-        match token_type {
-            TokenType::OpCode(opcode) => {
-                self.vm.write_opcode(opcode);
+        let token = self.advance();
+        let (line, column) = (token.line, token.column);
+        match (kind, token.token_type) {
+            (OperandKind::Register, TokenType::Register(register)) => {
+                Ok(Operand::Register(register))
+            }
+            (OperandKind::FloatRegister, TokenType::FloatRegister(register)) => {
+                Ok(Operand::FloatRegister(register))
             }
-            TokenType::Register(register) => {
-                self.vm.write_u8(register);
+            (OperandKind::Immediate, TokenType::Integer(integer)) => {
+                u16::try_from(integer).map(Operand::Immediate).map_err(|_| {
+                    format!(
+                        "Integer {} out of range 0..=65535 at {}:{}",
+                        integer, line, column
+                    )
+                })
             }
-            TokenType::Integer(integer) => {
-                self.vm.write_u16(integer);
+            (OperandKind::Signed, TokenType::Integer(integer)) => {
+                i16::try_from(integer).map(Operand::Signed).map_err(|_| {
+                    format!(
+                        "Integer {} out of range -32768..=32767 at {}:{}",
+                        integer, line, column
+                    )
+                })
             }
+            (OperandKind::Wide, TokenType::Integer(integer)) => Ok(Operand::Wide(integer)),
+            (OperandKind::Float, TokenType::Integer(integer)) => Ok(Operand::Float(integer as f64)),
+            (OperandKind::Float, TokenType::Float(float)) => Ok(Operand::Float(float)),
+            (kind, _) => Err(format!("Expected {} at {}:{}", kind, line, column)),
+        }
+    }
+
+    // Looks ahead at the operands of a `load` instruction
+    fn loaded_value_fits_u16(&self) -> bool {
+        match self
+            .tokens
+            .get(self.current + 1)
+            .map(|token| token.token_type)
+        {
+            Some(TokenType::Integer(value)) => u16::try_from(value).is_ok(),
+            _ => true,
         }
     }
 
@@ -0,0 +1,459 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Range;
+
+// A peripheral mapped into the guest's address space with `VM::attach_device`
+// Loads and stores inside the device's range are routed to it instead of the VM's memory
+pub trait Device {
+    // Number of bytes of address space the device occupies
+    fn size(&self) -> usize;
+
+    // Called by a load of `width` bytes at `offset` from the start of the device
+    // Only the low `width` bytes of the result are used, and they are zero-extended like memory loads
+    fn read(&mut self, offset: usize, width: usize) -> i64;
+
+    // Called by a store of the low `width` bytes of `value`, zero-extended, at `offset` from the start of the device
+    fn write(&mut self, offset: usize, width: usize, value: i64);
+
+    // Called after every instruction the VM completes, returning an interrupt to queue if any
+    // Instructions that fault are not counted, even if a trap handler recovers from them
+    fn tick(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+// Reasons `VM::attach_device` can reject a device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceError {
+    // The end of the device's range does not fit in a usize
+    AddressOverflow { address: usize, size: usize },
+    // The device's range overlaps the range of a device that is already attached
+    Overlap { range: Range<usize> },
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceError::AddressOverflow { address, size } => write!(
+                f,
+                "{}-byte device at {} overflows the address space",
+                size, address
+            ),
+            DeviceError::Overlap { range } => write!(
+                f,
+                "device at {}..{} overlaps an attached device",
+                range.start, range.end
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+// Character console
+// Offset 0: storing writes a byte to the output, loading reads the next input byte or 0 if there is none
+// Offset 1: loading reads the number of pending input bytes, which tells a 0 byte from no input
+#[derive(Clone, Debug, Default)]
+pub struct ConsoleDevice {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl ConsoleDevice {
+    pub const DATA: usize = 0;
+    pub const STATUS: usize = 1;
+
+    pub fn new() -> ConsoleDevice {
+        ConsoleDevice::default()
+    }
+
+    // Makes bytes available to the guest
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    // The bytes written by the guest so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Device for ConsoleDevice {
+    fn size(&self) -> usize {
+        2
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> i64 {
+        match offset {
+            ConsoleDevice::DATA => self.input.pop_front().map_or(0, |byte| byte as i64),
+            ConsoleDevice::STATUS => self.input.len() as i64,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: i64) {
+        if offset == ConsoleDevice::DATA {
+            self.output.push(value as u8);
+        }
+    }
+}
+
+// Counts the instructions executed by the VM
+// Offset 0: loading reads the tick count, storing resets it to the stored value
+// Offset 8: the period, every `period` ticks the timer raises its interrupt (0, the default, disables it)
+#[derive(Clone, Debug)]
+pub struct TimerDevice {
+    ticks: u64,
+    period: u64,
+    interrupt: u8,
+}
+
+impl TimerDevice {
+    pub const TICKS: usize = 0;
+    pub const PERIOD: usize = 8;
+
+    // `interrupt` is the interrupt number raised when the period elapses
+    pub fn new(interrupt: u8) -> TimerDevice {
+        TimerDevice {
+            ticks: 0,
+            period: 0,
+            interrupt,
+        }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+}
+
+impl Device for TimerDevice {
+    fn size(&self) -> usize {
+        16
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> i64 {
+        match offset {
+            TimerDevice::TICKS => self.ticks as i64,
+            TimerDevice::PERIOD => self.period as i64,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: i64) {
+        match offset {
+            TimerDevice::TICKS => self.ticks = value as u64,
+            TimerDevice::PERIOD => self.period = value as u64,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) -> Option<u8> {
+        self.ticks = self.ticks.wrapping_add(1);
+        match self.period {
+            0 => None,
+            period if self.ticks.is_multiple_of(period) => Some(self.interrupt),
+            _ => None,
+        }
+    }
+}
+
+// Deterministic pseudo-random number generator (xorshift64*)
+// Offset 0: loading reads the next random value, storing reseeds the generator
+#[derive(Clone, Debug)]
+pub struct RngDevice {
+    state: u64,
+}
+
+impl RngDevice {
+    pub const VALUE: usize = 0;
+
+    // The same seed always produces the same sequence
+    pub fn new(seed: u64) -> RngDevice {
+        let mut rng = RngDevice { state: 0 };
+        rng.seed(seed);
+        rng
+    }
+
+    // xorshift gets stuck on a state of 0, so the seed is mixed with a constant
+    fn seed(&mut self, seed: u64) {
+        self.state = seed ^ 0x9e37_79b9_7f4a_7c15;
+        if self.state == 0 {
+            self.state = 1;
+        }
+    }
+
+    pub fn next_value(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl Device for RngDevice {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> i64 {
+        match offset {
+            RngDevice::VALUE => self.next_value() as i64,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: i64) {
+        if offset == RngDevice::VALUE {
+            self.seed(value as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod device_tests {
+    use super::*;
+    use crate::error::VmError;
+    use crate::opcode::OpCode;
+    use crate::vm::{HaltReason, VM};
+
+    const DEVICE_ADDRESS: u16 = 0x8000;
+
+    #[test]
+    fn test_console() {
+        let mut vm = VM::new();
+        let console = vm
+            .attach_device(DEVICE_ADDRESS as usize, ConsoleDevice::new())
+            .unwrap();
+        console.lock().unwrap().push_input(b"ok");
+
+        // Echo input bytes until there are none left
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(DEVICE_ADDRESS); // 2
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(1); // 5
+        vm.write_u16(DEVICE_ADDRESS + 1); // 6
+        vm.write_opcode(OpCode::LOAD); // 8
+        vm.write_u8(2); // 9
+        vm.write_u16(12); // 10
+        vm.write_opcode(OpCode::LD8); // 12
+        vm.write_u8(3); // 13
+        vm.write_u8(1); // 14
+        vm.write_opcode(OpCode::EQI); // 15
+        vm.write_u8(3); // 16
+        vm.write_u16(0); // 17
+        vm.write_opcode(OpCode::JEQ); // 19
+        vm.write_u8(4); // 20
+        vm.write_opcode(OpCode::LD8); // 21
+        vm.write_u8(3); // 22
+        vm.write_u8(0); // 23
+        vm.write_opcode(OpCode::ST8); // 24
+        vm.write_u8(0); // 25
+        vm.write_u8(3); // 26
+        vm.write_opcode(OpCode::JMP); // 27
+        vm.write_u8(2); // 28
+        vm.registers[4] = 29;
+
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+        assert_eq!(console.lock().unwrap().output(), b"ok");
+    }
+
+    #[test]
+    fn test_console_without_input() {
+        let mut console = ConsoleDevice::new();
+        console.push_input(&[0xff]);
+
+        assert_eq!(console.read(ConsoleDevice::STATUS, 1), 1);
+        assert_eq!(console.read(ConsoleDevice::DATA, 1), 0xff);
+        assert_eq!(console.read(ConsoleDevice::STATUS, 1), 0);
+        assert_eq!(console.read(ConsoleDevice::DATA, 1), 0);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut vm = VM::new();
+        let timer = vm
+            .attach_device(DEVICE_ADDRESS as usize, TimerDevice::new(0))
+            .unwrap();
+
+        // Poll the tick count until at least 10 instructions have run
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(DEVICE_ADDRESS); // 2
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(2); // 5
+        vm.write_u16(8); // 6
+        vm.write_opcode(OpCode::LD64); // 8
+        vm.write_u8(1); // 9
+        vm.write_u8(0); // 10
+        vm.write_opcode(OpCode::LTI); // 11
+        vm.write_u8(1); // 12
+        vm.write_u16(10); // 13
+        vm.write_opcode(OpCode::JEQ); // 15
+        vm.write_u8(2); // 16
+
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+        // The count is read before the LD64 instruction itself has finished
+        assert_eq!(vm.registers[1], 11);
+        assert_eq!(timer.lock().unwrap().ticks(), 14);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut vm = VM::new();
+        vm.attach_device(DEVICE_ADDRESS as usize, TimerDevice::new(1))
+            .unwrap();
+
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(DEVICE_ADDRESS + TimerDevice::PERIOD as u16); // 2
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(1); // 5
+        vm.write_u16(5); // 6
+        vm.write_opcode(OpCode::ST64); // 8
+        vm.write_u8(0); // 9
+        vm.write_u8(1); // 10
+        vm.write_opcode(OpCode::LOAD); // 11
+        vm.write_u8(2); // 12
+        vm.write_u16(15); // 13
+        vm.write_opcode(OpCode::JMP); // 15
+        vm.write_u8(2); // 16
+        vm.write_opcode(OpCode::INC); // 17
+        vm.write_u8(3); // 18
+        vm.write_opcode(OpCode::RETI); // 19
+        vm.set_interrupt_vector(1, 17);
+
+        assert_eq!(vm.run_with_fuel(100), Ok(HaltReason::OutOfFuel));
+        assert!(vm.registers[3] > 1);
+    }
+
+    #[test]
+    fn test_rng() {
+        let mut vm = VM::new();
+        vm.attach_device(DEVICE_ADDRESS as usize, RngDevice::new(42))
+            .unwrap();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(DEVICE_ADDRESS); // 2
+        vm.write_opcode(OpCode::LD64); // 4
+        vm.write_u8(1); // 5
+        vm.write_u8(0); // 6
+        vm.write_opcode(OpCode::LD8); // 7
+        vm.write_u8(2); // 8
+        vm.write_u8(0); // 9
+        vm.run().unwrap();
+
+        let mut rng = RngDevice::new(42);
+        assert_eq!(vm.registers[1], rng.next_value() as i64);
+        assert_eq!(vm.registers[2], (rng.next_value() & 0xff) as i64);
+        assert_ne!(vm.registers[1], RngDevice::new(43).next_value() as i64);
+    }
+
+    #[test]
+    fn test_attach_errors() {
+        let mut vm = VM::new();
+        vm.attach_device(16, RngDevice::new(0)).unwrap();
+
+        assert_eq!(
+            vm.attach_device(20, ConsoleDevice::new()).unwrap_err(),
+            DeviceError::Overlap { range: 20..22 }
+        );
+        assert_eq!(
+            vm.attach_device(usize::MAX, ConsoleDevice::new())
+                .unwrap_err(),
+            DeviceError::AddressOverflow {
+                address: usize::MAX,
+                size: 2
+            }
+        );
+        assert!(vm.attach_device(24, ConsoleDevice::new()).is_ok());
+    }
+
+    #[test]
+    fn test_faulting_instructions_do_not_tick() {
+        let mut vm = VM::new();
+        let timer = vm
+            .attach_device(DEVICE_ADDRESS as usize, TimerDevice::new(0))
+            .unwrap();
+        vm.write_opcode(OpCode::DIV); // 0
+        vm.write_u8(0); // 1
+        vm.write_u8(0); // 2
+        vm.write_u8(1); // 3
+
+        assert!(matches!(vm.run(), Err(VmError::DivisionByZero { pc: 0 })));
+        assert_eq!(timer.lock().unwrap().ticks(), 0);
+    }
+
+    #[test]
+    fn test_access_across_device_end() {
+        let mut vm = VM::new();
+        vm.attach_device(DEVICE_ADDRESS as usize, ConsoleDevice::new())
+            .unwrap();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(DEVICE_ADDRESS + 1); // 2
+        vm.write_opcode(OpCode::LD16); // 4
+        vm.write_u8(1); // 5
+        vm.write_u8(0); // 6
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::MemoryOutOfBounds {
+                pc: 4,
+                address: DEVICE_ADDRESS as i64 + 1,
+                width: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_access_across_device_start() {
+        let mut vm = VM::new();
+        vm.attach_device(DEVICE_ADDRESS as usize, ConsoleDevice::new())
+            .unwrap();
+        vm.registers[1] = -1;
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(DEVICE_ADDRESS - 4); // 2
+        vm.write_opcode(OpCode::ST64); // 4
+        vm.write_u8(0); // 5
+        vm.write_u8(1); // 6
+
+        // Nothing is written, neither to memory nor to the device
+        assert_eq!(
+            vm.run(),
+            Err(VmError::MemoryOutOfBounds {
+                pc: 4,
+                address: DEVICE_ADDRESS as i64 - 4,
+                width: 8
+            })
+        );
+        assert_eq!(
+            vm.read_memory(DEVICE_ADDRESS as usize - 4, 8),
+            Some(&[0; 8][..])
+        );
+
+        let mut vm = VM::new();
+        vm.attach_device(DEVICE_ADDRESS as usize, ConsoleDevice::new())
+            .unwrap();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(DEVICE_ADDRESS - 1); // 2
+        vm.write_opcode(OpCode::LD16); // 4
+        vm.write_u8(1); // 5
+        vm.write_u8(0); // 6
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::MemoryOutOfBounds {
+                pc: 4,
+                address: DEVICE_ADDRESS as i64 - 1,
+                width: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::error::VmError;
+use crate::instruction::{Instruction, Operand};
+
+// Width of the instruction text before the offset comment
+const COMMENT_COLUMN: usize = 24;
+
+// Turns bytecode into assembly source, with the byte offset of every instruction in a comment
+// Bytes that do not form a valid instruction are written with the `.byte` directive,
+// so assembling the output always gives back the same bytes
+pub fn disassemble(code: &[u8]) -> String {
+    let mut output = String::new();
+    let mut pc = 0;
+
+    while pc < code.len() {
+        let (text, len) = match Instruction::decode(code, pc) {
+            Ok((instruction, len)) => match format_instruction(&instruction) {
+                Some(text) => (text, len),
+                None => (format_bytes(&code[pc..pc + len]), len),
+            },
+            // Only the opcode is skipped, the following bytes may still be valid instructions
+            Err(VmError::UnknownOpcode { .. }) => (format_bytes(&code[pc..pc + 1]), 1),
+            // The rest of the code is too short to hold the instruction's operands
+            Err(_) => (format_bytes(&code[pc..]), code.len() - pc),
+        };
+
+        output.push_str(&format!("{:<COMMENT_COLUMN$} ! {}\n", text, pc));
+        pc += len;
+    }
+
+    output
+}
+
+// Returns None if an operand cannot be written as an assembler literal
+fn format_instruction(instruction: &Instruction) -> Option<String> {
+    let mut text = instruction.opcode().mnemonic()?.to_string();
+
+    for operand in instruction.operands() {
+        let operand = match operand {
+            Operand::Register(register) => format!("%{}", register),
+            Operand::FloatRegister(register) => format!("${}", register),
+            Operand::Immediate(value) => format!("#{}", value),
+            Operand::Signed(value) => format!("#{}", value),
+            Operand::Wide(value) => format!("#{}", value),
+            Operand::Float(value) => format!("#{}", format_float(value)?),
+        };
+        text.push(' ');
+        text.push_str(&operand);
+    }
+
+    Some(text)
+}
+
+// The lexer only accepts plain decimal floats, so infinities and NaN are not representable
+// Display prints the shortest decimal that parses back to the same value, without an exponent
+fn format_float(value: f64) -> Option<String> {
+    if !value.is_finite() {
+        return None;
+    }
+
+    let text = value.to_string();
+    if text.contains('.') {
+        Some(text)
+    } else {
+        // Keeps the literal a float, which also preserves the sign of -0.0
+        Some(format!("{}.0", text))
+    }
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    let values: Vec<String> = bytes.iter().map(|byte| format!("#{}", byte)).collect();
+    format!(".byte {}", values.join(" "))
+}
+
+#[cfg(test)]
+mod disassembler_tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::instruction::OperandKind;
+    use crate::opcode::OpCode;
+    use crate::vm::VM;
+
+    fn reassemble(code: &[u8]) -> Vec<u8> {
+        assemble(disassemble(code), VM::new()).unwrap().code
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let input =
+            String::from("load %0 #123\nloadw %1 #-5\nfload $2 #0.5\nadd %2 %0 %1\nsys #0\n");
+        let vm = assemble(input, VM::new()).unwrap();
+
+        assert_eq!(
+            disassemble(&vm.code),
+            "load %0 #123             ! 0\n\
+             loadw %1 #-5             ! 4\n\
+             fload $2 #0.5            ! 14\n\
+             add %2 %0 %1             ! 24\n\
+             sys #0                   ! 28\n"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut code = Vec::new();
+        for byte in 0..=u8::MAX {
+            let opcode = OpCode::from(byte);
+            let operands: Vec<Operand> = opcode
+                .operand_kinds()
+                .iter()
+                .map(|kind| match kind {
+                    OperandKind::Register => Operand::Register(255),
+                    OperandKind::FloatRegister => Operand::FloatRegister(7),
+                    OperandKind::Immediate => Operand::Immediate(65535),
+                    OperandKind::Signed => Operand::Signed(i16::MIN),
+                    OperandKind::Wide => Operand::Wide(i64::MIN),
+                    OperandKind::Float => Operand::Float(-1e-300),
+                })
+                .collect();
+            if let Some(instruction) = Instruction::from_operands(opcode, &operands) {
+                instruction.encode(&mut code);
+            }
+        }
+
+        assert_eq!(reassemble(&code), code);
+    }
+
+    #[test]
+    fn test_raw_bytes() {
+        // An unknown opcode, a small LOAD written as LOADW, and a truncated instruction
+        let mut code = vec![255];
+        Instruction::Loadw { dst: 0, imm: 1 }.encode(&mut code);
+        code.extend_from_slice(&[OpCode::ADD as u8, 1]);
+
+        let text = disassemble(&code);
+        assert_eq!(
+            text,
+            ".byte #255               ! 0\n\
+             loadw %0 #1              ! 1\n\
+             .byte #3 #1              ! 11\n"
+        );
+        assert_eq!(reassemble(&code), code);
+    }
+
+    #[test]
+    fn test_float_literals() {
+        let mut code = Vec::new();
+        for value in [-0.0, 1e300, f64::MIN_POSITIVE, f64::NAN, f64::INFINITY] {
+            Instruction::Fload { dst: 0, imm: value }.encode(&mut code);
+        }
+
+        // NaN and infinity have no literal, so they are written as raw bytes
+        let text = disassemble(&code);
+        assert!(text.starts_with("fload $0 #-0.0"));
+        assert_eq!(text.matches(".byte").count(), 2);
+        assert_eq!(reassemble(&code), code);
+    }
+
+    #[test]
+    fn test_examples() {
+        for example in ["examples/count.rm", "examples/factorial.rm"] {
+            let input = std::fs::read_to_string(example).unwrap();
+            let code = assemble(input, VM::new()).unwrap().code;
+
+            assert_eq!(reassemble(&code), code);
+        }
+    }
+}
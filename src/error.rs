@@ -0,0 +1,172 @@
+use std::fmt;
+
+// Errors raised by the VM while executing guest code
+// Every variant carries the program counter of the instruction that caused it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    // The byte at pc does not correspond to any instruction
+    UnknownOpcode {
+        pc: usize,
+        byte: u8,
+    },
+    // A SYS instruction referenced a syscall id that was never registered
+    UnknownSyscall {
+        id: u16,
+        pc: usize,
+    },
+    // A DIV instruction was executed with a divisor of zero
+    DivisionByZero {
+        pc: usize,
+    },
+    // An arithmetic instruction produced a result that does not fit in an i64
+    ArithmeticOverflow {
+        pc: usize,
+    },
+    // The code ends before all operands of the instruction at pc could be read
+    TruncatedInstruction {
+        pc: usize,
+    },
+    // A jump instruction tried to move the program counter outside of the code
+    JumpOutOfBounds {
+        pc: usize,
+        target: i64,
+    },
+    // A registered syscall reported a failure
+    SyscallFailed {
+        id: u16,
+        pc: usize,
+    },
+    // A load or store of `width` bytes at `address` reaches outside of memory
+    MemoryOutOfBounds {
+        pc: usize,
+        address: i64,
+        width: usize,
+    },
+    // A CALL or PUSH instruction exceeded the VM's stack limits
+    StackOverflow {
+        pc: usize,
+    },
+    // A RET or POP instruction was executed with an empty stack
+    StackUnderflow {
+        pc: usize,
+    },
+    // A TRAP or UNTRAP instruction referenced a kind that does not exist
+    InvalidTrapKind {
+        pc: usize,
+        kind: u16,
+    },
+    // A RETI instruction was executed outside of an interrupt handler, or a RET instruction inside of one
+    InvalidReturn {
+        pc: usize,
+    },
+}
+
+impl VmError {
+    // The program counter of the instruction that caused the error
+    pub fn pc(&self) -> usize {
+        match *self {
+            VmError::UnknownOpcode { pc, .. }
+            | VmError::UnknownSyscall { pc, .. }
+            | VmError::DivisionByZero { pc }
+            | VmError::ArithmeticOverflow { pc }
+            | VmError::TruncatedInstruction { pc }
+            | VmError::JumpOutOfBounds { pc, .. }
+            | VmError::SyscallFailed { pc, .. }
+            | VmError::MemoryOutOfBounds { pc, .. }
+            | VmError::StackOverflow { pc }
+            | VmError::StackUnderflow { pc }
+            | VmError::InvalidTrapKind { pc, .. }
+            | VmError::InvalidReturn { pc } => pc,
+        }
+    }
+
+    // What went wrong, without the location of the instruction
+    pub fn message(&self) -> String {
+        match self {
+            VmError::UnknownOpcode { byte, .. } => format!("unknown opcode {}", byte),
+            VmError::UnknownSyscall { id, .. } => format!("unknown syscall {}", id),
+            VmError::DivisionByZero { .. } => String::from("division by zero"),
+            VmError::ArithmeticOverflow { .. } => String::from("arithmetic overflow"),
+            VmError::TruncatedInstruction { .. } => String::from("truncated instruction"),
+            VmError::JumpOutOfBounds { target, .. } => {
+                format!("jump to {} out of bounds", target)
+            }
+            VmError::SyscallFailed { id, .. } => format!("syscall {} failed", id),
+            VmError::MemoryOutOfBounds { address, width, .. } => {
+                format!("{}-byte memory access at {} out of bounds", width, address)
+            }
+            VmError::StackOverflow { .. } => String::from("stack overflow"),
+            VmError::StackUnderflow { .. } => String::from("stack underflow"),
+            VmError::InvalidTrapKind { kind, .. } => format!("invalid trap kind {}", kind),
+            VmError::InvalidReturn { .. } => String::from("invalid return"),
+        }
+    }
+
+    // The kind of trap handler that can recover from this error
+    pub fn trap_kind(&self) -> TrapKind {
+        match self {
+            VmError::UnknownOpcode { .. } => TrapKind::UnknownOpcode,
+            VmError::UnknownSyscall { .. } => TrapKind::UnknownSyscall,
+            VmError::DivisionByZero { .. } => TrapKind::DivisionByZero,
+            VmError::ArithmeticOverflow { .. } => TrapKind::ArithmeticOverflow,
+            VmError::TruncatedInstruction { .. } => TrapKind::TruncatedInstruction,
+            VmError::JumpOutOfBounds { .. } => TrapKind::JumpOutOfBounds,
+            VmError::SyscallFailed { .. } => TrapKind::SyscallFailed,
+            VmError::MemoryOutOfBounds { .. } => TrapKind::MemoryOutOfBounds,
+            VmError::StackOverflow { .. } => TrapKind::StackOverflow,
+            VmError::StackUnderflow { .. } => TrapKind::StackUnderflow,
+            VmError::InvalidTrapKind { .. } => TrapKind::InvalidTrapKind,
+            VmError::InvalidReturn { .. } => TrapKind::InvalidReturn,
+        }
+    }
+}
+
+// Categories of runtime faults a guest program can install a handler for with the TRAP instruction
+// The guest refers to them by their code, which is also written to the trap cause register
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TrapKind {
+    UnknownOpcode = 1,
+    UnknownSyscall,
+    DivisionByZero,
+    ArithmeticOverflow,
+    TruncatedInstruction,
+    JumpOutOfBounds,
+    SyscallFailed,
+    MemoryOutOfBounds,
+    StackOverflow,
+    StackUnderflow,
+    InvalidTrapKind,
+    InvalidReturn,
+}
+
+impl TrapKind {
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+
+    pub fn from_code(code: u16) -> Option<TrapKind> {
+        match code {
+            1 => Some(TrapKind::UnknownOpcode),
+            2 => Some(TrapKind::UnknownSyscall),
+            3 => Some(TrapKind::DivisionByZero),
+            4 => Some(TrapKind::ArithmeticOverflow),
+            5 => Some(TrapKind::TruncatedInstruction),
+            6 => Some(TrapKind::JumpOutOfBounds),
+            7 => Some(TrapKind::SyscallFailed),
+            8 => Some(TrapKind::MemoryOutOfBounds),
+            9 => Some(TrapKind::StackOverflow),
+            10 => Some(TrapKind::StackUnderflow),
+            11 => Some(TrapKind::InvalidTrapKind),
+            12 => Some(TrapKind::InvalidReturn),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at pc {}", self.message(), self.pc())
+    }
+}
+
+impl std::error::Error for VmError {}
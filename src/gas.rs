@@ -0,0 +1,53 @@
+use crate::opcode::OpCode;
+
+// The amount of fuel each instruction consumes when the VM is running with limited fuel
+// Syscalls are charged the cost of the SYS opcode plus the cost given to `VM::register_syscall_with_cost`
+#[derive(Clone)]
+pub struct GasSchedule {
+    // Indexed by the opcode's byte value
+    costs: [u64; 256],
+}
+
+impl Default for GasSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GasSchedule {
+    // Every instruction costs 1 fuel
+    pub fn new() -> GasSchedule {
+        GasSchedule::uniform(1)
+    }
+
+    // Every instruction costs the same amount of fuel
+    pub fn uniform(cost: u64) -> GasSchedule {
+        GasSchedule { costs: [cost; 256] }
+    }
+
+    pub fn set_cost(&mut self, opcode: OpCode, cost: u64) {
+        self.costs[opcode as usize] = cost;
+    }
+
+    pub fn cost(&self, opcode: OpCode) -> u64 {
+        self.costs[opcode as usize]
+    }
+}
+
+#[cfg(test)]
+mod gas_tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let mut schedule = GasSchedule::new();
+        assert_eq!(schedule.cost(OpCode::ADD), 1);
+
+        schedule.set_cost(OpCode::DIV, 10);
+        assert_eq!(schedule.cost(OpCode::DIV), 10);
+        assert_eq!(schedule.cost(OpCode::MUL), 1);
+
+        let schedule = GasSchedule::uniform(0);
+        assert_eq!(schedule.cost(OpCode::SYS), 0);
+    }
+}
@@ -0,0 +1,393 @@
+use std::fmt;
+
+use crate::error::VmError;
+use crate::opcode::OpCode;
+
+// The kinds of operands an instruction can take, in the order they are encoded
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    // An integer register (u8)
+    Register,
+    // A float register (u8)
+    FloatRegister,
+    // An unsigned 16-bit immediate value
+    Immediate,
+    // A signed 16-bit immediate value, sign-extended when used
+    Signed,
+    // A signed 64-bit immediate value
+    Wide,
+    // A 64-bit floating-point immediate value
+    Float,
+}
+
+impl fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OperandKind::Register => write!(f, "register"),
+            OperandKind::FloatRegister => write!(f, "float register"),
+            OperandKind::Immediate | OperandKind::Signed | OperandKind::Wide => {
+                write!(f, "integer")
+            }
+            OperandKind::Float => write!(f, "float"),
+        }
+    }
+}
+
+// The value of a single operand, used to build instructions generically (e.g. in the assembler)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operand {
+    Register(u8),
+    FloatRegister(u8),
+    Immediate(u16),
+    Signed(i16),
+    Wide(i64),
+    Float(f64),
+}
+
+// Fixed-size big-endian encoding of an operand value
+trait Encoding: Sized {
+    const SIZE: usize;
+
+    fn from_bytes(bytes: &[u8]) -> Self;
+    fn write(self, code: &mut Vec<u8>);
+}
+
+macro_rules! encodings {
+    ($($t:ty),*) => {
+        $(
+            impl Encoding for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn from_bytes(bytes: &[u8]) -> Self {
+                    <$t>::from_be_bytes(bytes.try_into().unwrap())
+                }
+
+                fn write(self, code: &mut Vec<u8>) {
+                    code.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+        )*
+    };
+}
+
+encodings!(u8, u16, i16, i64, f64);
+
+// Reads the operand at `offset` and moves past it
+// `pc` is the start of the instruction, used for error reporting
+fn read<T: Encoding>(code: &[u8], offset: &mut usize, pc: usize) -> Result<T, VmError> {
+    let bytes = code
+        .get(*offset..*offset + T::SIZE)
+        .ok_or(VmError::TruncatedInstruction { pc })?;
+    *offset += T::SIZE;
+    Ok(T::from_bytes(bytes))
+}
+
+macro_rules! operand_type {
+    (Register) => {
+        u8
+    };
+    (FloatRegister) => {
+        u8
+    };
+    (Immediate) => {
+        u16
+    };
+    (Signed) => {
+        i16
+    };
+    (Wide) => {
+        i64
+    };
+    (Float) => {
+        f64
+    };
+}
+
+// Defines `Instruction` along with its encoding, from the operands of every opcode
+macro_rules! instructions {
+    ($($variant:ident = $opcode:ident $mnemonic:literal { $($field:ident: $kind:ident),* },)*) => {
+        // A decoded instruction, with one variant per opcode
+        // Registers are written in the order of the assembly syntax described in `OpCode`
+        #[derive(Copy, Clone, Debug, PartialEq)]
+        pub enum Instruction {
+            $($variant { $($field: operand_type!($kind)),* },)*
+        }
+
+        impl Instruction {
+            pub fn opcode(&self) -> OpCode {
+                match self {
+                    $(Instruction::$variant { .. } => OpCode::$opcode,)*
+                }
+            }
+
+            // Appends the bytecode of the instruction to `code`
+            pub fn encode(&self, code: &mut Vec<u8>) {
+                match *self {
+                    $(Instruction::$variant { $($field),* } => {
+                        code.push(OpCode::$opcode as u8);
+                        $($field.write(code);)*
+                    })*
+                }
+            }
+
+            // Decodes the instruction starting at `pc`, returning it along with its length in bytes
+            pub fn decode(code: &[u8], pc: usize) -> Result<(Instruction, usize), VmError> {
+                let byte = *code.get(pc).ok_or(VmError::TruncatedInstruction { pc })?;
+                let mut offset = pc + 1;
+
+                let instruction = match OpCode::from(byte) {
+                    $(OpCode::$opcode => {
+                        $(let $field = read::<operand_type!($kind)>(code, &mut offset, pc)?;)*
+                        Instruction::$variant { $($field),* }
+                    })*
+                    OpCode::UKWN => return Err(VmError::UnknownOpcode { pc, byte }),
+                };
+
+                Ok((instruction, offset - pc))
+            }
+
+            pub fn operands(&self) -> Vec<Operand> {
+                match *self {
+                    $(Instruction::$variant { $($field),* } => vec![$(Operand::$kind($field)),*],)*
+                }
+            }
+
+            // Builds an instruction from its opcode and operands
+            // Returns None if the operands do not match `opcode.operand_kinds()`
+            pub fn from_operands(opcode: OpCode, operands: &[Operand]) -> Option<Instruction> {
+                match (opcode, operands) {
+                    $((OpCode::$opcode, [$(Operand::$kind($field)),*]) => {
+                        Some(Instruction::$variant { $($field: *$field),* })
+                    })*
+                    _ => None,
+                }
+            }
+        }
+
+        impl OpCode {
+            // The operands the instruction takes, in the order they are encoded
+            pub fn operand_kinds(&self) -> &'static [OperandKind] {
+                match self {
+                    $(OpCode::$opcode => &[$(OperandKind::$kind),*],)*
+                    OpCode::UKWN => &[],
+                }
+            }
+
+            // The mnemonic used by the assembler, or None for UKWN
+            pub fn mnemonic(&self) -> Option<&'static str> {
+                match self {
+                    $(OpCode::$opcode => Some($mnemonic),)*
+                    OpCode::UKWN => None,
+                }
+            }
+
+            // Mnemonics are lowercase, the assembler lowercases its input before looking them up
+            pub fn from_mnemonic(mnemonic: &str) -> Option<OpCode> {
+                match mnemonic {
+                    $($mnemonic => Some(OpCode::$opcode),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+instructions! {
+    Stop = STOP "stop" {},
+    Load = LOAD "load" { dst: Register, imm: Immediate },
+    Mov = MOV "mov" { dst: Register, src: Register },
+    Add = ADD "add" { dst: Register, a: Register, b: Register },
+    Sub = SUB "sub" { dst: Register, a: Register, b: Register },
+    Mul = MUL "mul" { dst: Register, a: Register, b: Register },
+    Div = DIV "div" { dst: Register, a: Register, b: Register },
+    Jmp = JMP "jmp" { addr: Register },
+    Jfw = JFW "jfw" { offset: Register },
+    Jbk = JBK "jbk" { offset: Register },
+    Eq = EQ "eq" { a: Register, b: Register },
+    Neq = NEQ "neq" { a: Register, b: Register },
+    Gt = GT "gt" { a: Register, b: Register },
+    Lt = LT "lt" { a: Register, b: Register },
+    Gte = GTE "gte" { a: Register, b: Register },
+    Lte = LTE "lte" { a: Register, b: Register },
+    Jeq = JEQ "jeq" { addr: Register },
+    Jne = JNE "jne" { addr: Register },
+    Sys = SYS "sys" { id: Immediate },
+    Ld8 = LD8 "ld8" { dst: Register, addr: Register },
+    Ld16 = LD16 "ld16" { dst: Register, addr: Register },
+    Ld32 = LD32 "ld32" { dst: Register, addr: Register },
+    Ld64 = LD64 "ld64" { dst: Register, addr: Register },
+    St8 = ST8 "st8" { addr: Register, src: Register },
+    St16 = ST16 "st16" { addr: Register, src: Register },
+    St32 = ST32 "st32" { addr: Register, src: Register },
+    St64 = ST64 "st64" { addr: Register, src: Register },
+    Call = CALL "call" { addr: Register },
+    Ret = RET "ret" {},
+    Push = PUSH "push" { src: Register },
+    Pop = POP "pop" { dst: Register },
+    Jz = JZ "jz" { addr: Register },
+    Jnz = JNZ "jnz" { addr: Register },
+    Jlt = JLT "jlt" { addr: Register },
+    Jge = JGE "jge" { addr: Register },
+    Jgt = JGT "jgt" { addr: Register },
+    Jle = JLE "jle" { addr: Register },
+    Jc = JC "jc" { addr: Register },
+    Jnc = JNC "jnc" { addr: Register },
+    Jo = JO "jo" { addr: Register },
+    Jno = JNO "jno" { addr: Register },
+    Addt = ADDT "addt" { dst: Register, a: Register, b: Register },
+    Subt = SUBT "subt" { dst: Register, a: Register, b: Register },
+    Mult = MULT "mult" { dst: Register, a: Register, b: Register },
+    Addw = ADDW "addw" { dst: Register, a: Register, b: Register },
+    Subw = SUBW "subw" { dst: Register, a: Register, b: Register },
+    Mulw = MULW "mulw" { dst: Register, a: Register, b: Register },
+    Adds = ADDS "adds" { dst: Register, a: Register, b: Register },
+    Subs = SUBS "subs" { dst: Register, a: Register, b: Register },
+    Muls = MULS "muls" { dst: Register, a: Register, b: Register },
+    And = AND "and" { dst: Register, a: Register, b: Register },
+    Or = OR "or" { dst: Register, a: Register, b: Register },
+    Xor = XOR "xor" { dst: Register, a: Register, b: Register },
+    Not = NOT "not" { dst: Register, src: Register },
+    Shl = SHL "shl" { dst: Register, a: Register, b: Register },
+    Shr = SHR "shr" { dst: Register, a: Register, b: Register },
+    Sar = SAR "sar" { dst: Register, a: Register, b: Register },
+    Mod = MOD "mod" { dst: Register, a: Register, b: Register },
+    Neg = NEG "neg" { dst: Register, src: Register },
+    Abs = ABS "abs" { dst: Register, src: Register },
+    Min = MIN "min" { dst: Register, a: Register, b: Register },
+    Max = MAX "max" { dst: Register, a: Register, b: Register },
+    Inc = INC "inc" { reg: Register },
+    Dec = DEC "dec" { reg: Register },
+    Addi = ADDI "addi" { dst: Register, src: Register, imm: Signed },
+    Subi = SUBI "subi" { dst: Register, src: Register, imm: Signed },
+    Muli = MULI "muli" { dst: Register, src: Register, imm: Signed },
+    Eqi = EQI "eqi" { reg: Register, imm: Signed },
+    Neqi = NEQI "neqi" { reg: Register, imm: Signed },
+    Gti = GTI "gti" { reg: Register, imm: Signed },
+    Lti = LTI "lti" { reg: Register, imm: Signed },
+    Gtei = GTEI "gtei" { reg: Register, imm: Signed },
+    Ltei = LTEI "ltei" { reg: Register, imm: Signed },
+    Loadw = LOADW "loadw" { dst: Register, imm: Wide },
+    Fload = FLOAD "fload" { dst: FloatRegister, imm: Float },
+    Fmov = FMOV "fmov" { dst: FloatRegister, src: FloatRegister },
+    Fadd = FADD "fadd" { dst: FloatRegister, a: FloatRegister, b: FloatRegister },
+    Fsub = FSUB "fsub" { dst: FloatRegister, a: FloatRegister, b: FloatRegister },
+    Fmul = FMUL "fmul" { dst: FloatRegister, a: FloatRegister, b: FloatRegister },
+    Fdiv = FDIV "fdiv" { dst: FloatRegister, a: FloatRegister, b: FloatRegister },
+    Feq = FEQ "feq" { a: FloatRegister, b: FloatRegister },
+    Fneq = FNEQ "fneq" { a: FloatRegister, b: FloatRegister },
+    Fgt = FGT "fgt" { a: FloatRegister, b: FloatRegister },
+    Flt = FLT "flt" { a: FloatRegister, b: FloatRegister },
+    Fgte = FGTE "fgte" { a: FloatRegister, b: FloatRegister },
+    Flte = FLTE "flte" { a: FloatRegister, b: FloatRegister },
+    Itof = ITOF "itof" { dst: FloatRegister, src: Register },
+    Ftoi = FTOI "ftoi" { dst: Register, src: FloatRegister },
+    Trap = TRAP "trap" { kind: Immediate, addr: Register },
+    Untrap = UNTRAP "untrap" { kind: Immediate },
+    Ei = EI "ei" {},
+    Di = DI "di" {},
+    Reti = RETI "reti" {},
+}
+
+#[cfg(test)]
+mod instruction_tests {
+    use super::*;
+
+    // An instruction for every opcode, with distinct operand values
+    fn every_instruction() -> Vec<Instruction> {
+        (0..=u8::MAX)
+            .map(OpCode::from)
+            .filter(|opcode| !matches!(opcode, OpCode::UKWN))
+            .map(|opcode| {
+                let operands: Vec<Operand> = opcode
+                    .operand_kinds()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, kind)| match kind {
+                        OperandKind::Register => Operand::Register(i as u8 + 1),
+                        OperandKind::FloatRegister => Operand::FloatRegister(i as u8 + 2),
+                        OperandKind::Immediate => Operand::Immediate(0x1234),
+                        OperandKind::Signed => Operand::Signed(-0x1234),
+                        OperandKind::Wide => Operand::Wide(-5),
+                        OperandKind::Float => Operand::Float(0.5),
+                    })
+                    .collect();
+                Instruction::from_operands(opcode, &operands).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let instructions = every_instruction();
+        assert_eq!(instructions.len(), OpCode::UKWN as usize);
+
+        let mut code = Vec::new();
+        for instruction in &instructions {
+            instruction.encode(&mut code);
+        }
+
+        let mut pc = 0;
+        for instruction in &instructions {
+            let (decoded, len) = Instruction::decode(&code, pc).unwrap();
+            assert_eq!(&decoded, instruction);
+            assert_eq!(decoded.opcode() as u8, code[pc]);
+            assert_eq!(decoded.operands(), instruction.operands());
+            pc += len;
+        }
+        assert_eq!(pc, code.len());
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        for opcode in (0..=u8::MAX).map(OpCode::from) {
+            match opcode.mnemonic() {
+                Some(mnemonic) => assert_eq!(OpCode::from(mnemonic), opcode),
+                None => assert_eq!(opcode, OpCode::UKWN),
+            }
+        }
+        assert_eq!(OpCode::JFW.mnemonic(), Some("jfw"));
+        assert_eq!(OpCode::from("jmpf"), OpCode::UKWN);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut code = Vec::new();
+        Instruction::Add { dst: 2, a: 0, b: 1 }.encode(&mut code);
+        Instruction::Load { dst: 1, imm: 513 }.encode(&mut code);
+        Instruction::Stop {}.encode(&mut code);
+
+        assert_eq!(
+            code,
+            vec![OpCode::ADD as u8, 2, 0, 1, OpCode::LOAD as u8, 1, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            Instruction::decode(&[0, 255], 1),
+            Err(VmError::UnknownOpcode { pc: 1, byte: 255 })
+        );
+        assert_eq!(
+            Instruction::decode(&[OpCode::LOAD as u8, 0, 1], 0),
+            Err(VmError::TruncatedInstruction { pc: 0 })
+        );
+        assert_eq!(
+            Instruction::decode(&[], 0),
+            Err(VmError::TruncatedInstruction { pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_from_operands() {
+        assert_eq!(
+            Instruction::from_operands(OpCode::INC, &[Operand::Register(3)]),
+            Some(Instruction::Inc { reg: 3 })
+        );
+        assert_eq!(
+            Instruction::from_operands(OpCode::INC, &[Operand::FloatRegister(3)]),
+            None
+        );
+        assert_eq!(Instruction::from_operands(OpCode::INC, &[]), None);
+    }
+}
@@ -1,3 +1,12 @@
 pub mod assembler;
+pub mod device;
+pub mod disassembler;
+pub mod error;
+pub mod gas;
+pub mod instruction;
+pub mod line_table;
 pub mod opcode;
+pub mod program;
+pub mod syscall;
+pub mod verifier;
 pub mod vm;
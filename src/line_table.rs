@@ -0,0 +1,133 @@
+use std::fmt;
+use std::ops::Range;
+
+// Where an instruction comes from in the assembly source
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation<'a> {
+    // Empty if the source was not read from a file
+    pub file: &'a str,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "{}:{}", self.line, self.column)
+        } else {
+            write!(f, "{}:{}:{}", self.file, self.line, self.column)
+        }
+    }
+}
+
+// The bytes from `start` to `end` were assembled from `line` and `column` of `files[file]`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LineEntry {
+    pub start: usize,
+    pub end: usize,
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Maps code addresses back to the assembly source, filled in by the assembler
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LineTable {
+    files: Vec<String>,
+    // Ordered by address, without overlaps
+    entries: Vec<LineEntry>,
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable::default()
+    }
+
+    // Entries must be added in address order, as the assembler writes the code
+    // Returns false without adding anything if `range` is empty or starts before the end of the last entry
+    pub fn add(&mut self, file: &str, range: Range<usize>, line: usize, column: usize) -> bool {
+        let last_end = self.entries.last().map_or(0, |entry| entry.end);
+        if range.is_empty() || range.start < last_end {
+            return false;
+        }
+
+        let file = match self.files.iter().position(|name| name == file) {
+            Some(index) => index,
+            None => {
+                self.files.push(file.to_string());
+                self.files.len() - 1
+            }
+        };
+        self.entries.push(LineEntry {
+            start: range.start,
+            end: range.end,
+            file,
+            line,
+            column,
+        });
+        true
+    }
+
+    // The source location of the instruction containing `pc`
+    pub fn lookup(&self, pc: usize) -> Option<SourceLocation<'_>> {
+        let index = self.entries.partition_point(|entry| entry.start <= pc);
+        let entry = self.entries[..index].last()?;
+        if pc >= entry.end {
+            return None;
+        }
+
+        Some(SourceLocation {
+            file: &self.files[entry.file],
+            line: entry.line,
+            column: entry.column,
+        })
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn entries(&self) -> &[LineEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod line_table_tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut table = LineTable::new();
+        assert!(table.add("main.rm", 0..4, 1, 1));
+        assert!(table.add("main.rm", 4..6, 2, 3));
+        assert!(table.add("lib.rm", 10..12, 7, 1));
+
+        assert_eq!(table.files(), ["main.rm", "lib.rm"]);
+        assert_eq!(table.lookup(3).unwrap().to_string(), "main.rm:1:1");
+        assert_eq!(table.lookup(4).unwrap().to_string(), "main.rm:2:3");
+        assert_eq!(table.lookup(11).unwrap().to_string(), "lib.rm:7:1");
+        assert_eq!(table.lookup(6), None);
+        assert_eq!(table.lookup(12), None);
+    }
+
+    #[test]
+    fn test_add_out_of_order() {
+        let mut table = LineTable::new();
+        assert!(table.add("", 4..8, 2, 1));
+        assert!(!table.add("", 0..4, 1, 1));
+        assert!(!table.add("", 8..8, 3, 1));
+
+        assert_eq!(table.entries().len(), 1);
+        assert_eq!(table.lookup(5).unwrap().to_string(), "2:1");
+    }
+}
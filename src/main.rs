@@ -1,4 +1,6 @@
-use register_machine::{assembler::assemble, vm::VM};
+use register_machine::{
+    assembler::assemble_file, disassembler::disassemble, program::Program, vm::VM,
+};
 
 mod repl;
 
@@ -8,25 +10,85 @@ fn main() {
 
     if args.len() == 1 {
         repl::start_repl();
+    } else if args[1] == "disassemble" {
+        // Print the assembly of a program file, or of a file containing raw bytecode
+        let Some(filename) = args.get(2) else {
+            eprintln!("Usage: {} disassemble <file>", args[0]);
+            std::process::exit(1);
+        };
+        let bytes = std::fs::read(filename).unwrap();
+
+        let code = if Program::is_program(&bytes) {
+            match Program::from_bytes(&bytes) {
+                Ok(program) => program.code,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            bytes
+        };
+
+        print!("{}", disassemble(&code));
+    } else if args[1] == "assemble" {
+        // Save a source file as a program file, which can be run without assembling it again
+        let (Some(filename), Some(output)) = (args.get(2), args.get(3)) else {
+            eprintln!("Usage: {} assemble <source> <output>", args[0]);
+            std::process::exit(1);
+        };
+        let input = std::fs::read_to_string(filename).unwrap();
+        let vm = assemble_file(filename, input, VM::new()).expect("Failed to assemble program");
+
+        // The line table is kept, so errors still point at the source
+        let program = Program {
+            line_table: vm.line_table,
+            ..Program::new(vm.code)
+        };
+        if let Err(e) = program.save(output) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
     } else {
         // If arguments are passed, read the file and run the program
+        // The file can either be a program file or assembly source
         let filename = &args[1];
-        let input = std::fs::read_to_string(filename).unwrap();
+        let bytes = std::fs::read(filename).unwrap();
 
-        let mut vm = assemble(input, VM::new()).expect("Failed to assemble program");
+        let mut vm = if Program::is_program(&bytes) {
+            let mut vm = VM::new();
+            if let Err(e) =
+                Program::from_bytes(&bytes).and_then(|program| program.load_into(&mut vm))
+            {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            vm
+        } else {
+            let input = String::from_utf8(bytes).unwrap();
+            assemble_file(filename, input, VM::new()).expect("Failed to assemble program")
+        };
 
         // Print syscall
-        // Print the value a register
-        // Register to print stored at %80
-        vm.register_syscall(0, |vm| {
-            let register = vm.registers[80];
-            let value = vm.registers[register as usize];
-
+        // Prints the value of its first argument, %1
+        vm.register_syscall_with_args(0, 1, |args| {
+            let value: i64 = args.get(0)?;
             println!("{}", value);
 
-            true
+            Ok(())
         });
 
-        vm.run();
+        // Reject code that would stop the VM before running any of it
+        if let Err(errors) = vm.verify() {
+            for e in errors {
+                eprintln!("Error: {}", e);
+            }
+            std::process::exit(1);
+        }
+
+        if let Err(e) = vm.run() {
+            eprintln!("Error: {}", vm.describe_error(&e));
+            std::process::exit(1);
+        }
     }
 }
@@ -1,8 +1,10 @@
 // Instruction comment format:
 // registers (u8) are indicated by <reg>
+// float registers (u8) are indicated by <freg>
 // values (u16) are indicated by [value]
+// signed values (i16, sign-extended to 64 bits) are indicated by <simm>
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum OpCode {
     // Stops the VM execution
     STOP,
@@ -71,6 +73,224 @@ pub enum OpCode {
     // Allows adding custom functionality to the VM
     // sys [syscall_id]
     SYS,
+    // Load a value from memory into a register, the address is stored in a register
+    // Narrow loads are zero-extended to 64 bits, values are stored in big-endian order
+    // ld8 <dst> <addr>
+    // dst = memory[addr]
+    LD8,
+    LD16,
+    LD32,
+    LD64,
+    // Store the low bits of a register into memory, the address is stored in a register
+    // st8 <addr> <src>
+    // memory[addr] = src
+    ST8,
+    ST16,
+    ST32,
+    ST64,
+    // Call a subroutine, address is stored in a register
+    // The return address is pushed onto the call stack
+    // call <addr>
+    CALL,
+    // Return from a subroutine to the address on top of the call stack
+    // ret
+    RET,
+    // Push the value of a register onto the stack
+    // push <src>
+    PUSH,
+    // Pop the value on top of the stack into a register
+    // pop <dst>
+    POP,
+    // Jump to an instruction if the zero flag is set
+    // The flags are set by arithmetic and comparison instructions, comparisons set them from reg1 - reg2
+    // jz <addr>
+    JZ,
+    // Jump to an instruction if the zero flag is not set
+    // jnz <addr>
+    JNZ,
+    // Jump to an instruction if the last comparison was signed less than (negative != overflow)
+    // jlt <addr>
+    JLT,
+    // Jump to an instruction if the last comparison was signed greater than or equal (negative == overflow)
+    // jge <addr>
+    JGE,
+    // Jump to an instruction if the last comparison was signed greater than (!zero && negative == overflow)
+    // jgt <addr>
+    JGT,
+    // Jump to an instruction if the last comparison was signed less than or equal (zero || negative != overflow)
+    // jle <addr>
+    JLE,
+    // Jump to an instruction if the carry flag is set
+    // jc <addr>
+    JC,
+    // Jump to an instruction if the carry flag is not set
+    // jnc <addr>
+    JNC,
+    // Jump to an instruction if the overflow flag is set
+    // jo <addr>
+    JO,
+    // Jump to an instruction if the overflow flag is not set
+    // jno <addr>
+    JNO,
+    // Arithmetic with an explicit overflow behaviour, ignoring the VM's arithmetic mode
+    // ADD, SUB and MUL follow the mode set by the host, which is checked by default
+    // Checked variants stop the VM on overflow
+    // addt <dst> <src1> <src2>
+    ADDT,
+    SUBT,
+    MULT,
+    // Wrapping variants wrap around using two's complement
+    // addw <dst> <src1> <src2>
+    ADDW,
+    SUBW,
+    MULW,
+    // Saturating variants clamp the result to the smallest or largest i64
+    // adds <dst> <src1> <src2>
+    ADDS,
+    SUBS,
+    MULS,
+    // Bitwise and of two registers
+    // and <dst> <src1> <src2>
+    // dst = src1 & src2
+    AND,
+    // Bitwise or of two registers
+    // or <dst> <src1> <src2>
+    // dst = src1 | src2
+    OR,
+    // Bitwise exclusive or of two registers
+    // xor <dst> <src1> <src2>
+    // dst = src1 ^ src2
+    XOR,
+    // Bitwise not of a register
+    // not <dst> <src>
+    // dst = !src
+    NOT,
+    // Shift left, the amount is read as unsigned and amounts of 64 or more give 0
+    // shl <dst> <src> <amount>
+    // dst = src << amount
+    SHL,
+    // Logical shift right, filling with zeros, amounts of 64 or more give 0
+    // shr <dst> <src> <amount>
+    SHR,
+    // Arithmetic shift right, filling with the sign bit, amounts of 64 or more give 0 or -1
+    // sar <dst> <src> <amount>
+    SAR,
+    // Remainder of dividing two registers, the result has the sign of src1 so that
+    // src1 == (src1 / src2) * src2 + (src1 % src2)
+    // mod <dst> <src1> <src2>
+    // dst = src1 % src2
+    MOD,
+    // Negate a register
+    // neg <dst> <src>
+    // dst = -src
+    NEG,
+    // Absolute value of a register
+    // abs <dst> <src>
+    // dst = |src|
+    ABS,
+    // Smallest of two registers
+    // min <dst> <src1> <src2>
+    MIN,
+    // Largest of two registers
+    // max <dst> <src1> <src2>
+    MAX,
+    // Increment a register by one
+    // inc <reg>
+    // reg = reg + 1
+    INC,
+    // Decrement a register by one
+    // dec <reg>
+    // reg = reg - 1
+    DEC,
+    // Add an immediate value to a register and store the result in a register
+    // Immediate values are signed 16-bit integers, sign-extended to 64 bits
+    // addi <dst> <src> <simm>
+    // dst = src + simm
+    ADDI,
+    // Subtract an immediate value from a register and store the result in a register
+    // subi <dst> <src> <simm>
+    // dst = src - simm
+    SUBI,
+    // Multiply a register by an immediate value and store the result in a register
+    // muli <dst> <src> <simm>
+    // dst = src * simm
+    MULI,
+    // Compare a register with an immediate value, setting the comparison flag and the flags
+    // like their register counterparts
+    // eqi <reg> <simm>
+    // equality = reg == simm
+    EQI,
+    // neqi <reg> <simm>
+    NEQI,
+    // gti <reg> <simm>
+    GTI,
+    // lti <reg> <simm>
+    LTI,
+    // gtei <reg> <simm>
+    GTEI,
+    // ltei <reg> <simm>
+    LTEI,
+    // Load a signed 64-bit value into a register
+    // The assembler picks LOAD or LOADW for `load` depending on the value
+    // loadw <dst> [value]
+    LOADW,
+    // Load a 64-bit floating-point value into a float register
+    // fload <fdst> [value]
+    FLOAD,
+    // Move a value from one float register to another
+    // fmov <fdst> <fsrc>
+    FMOV,
+    // Floating-point arithmetic on two float registers, following IEEE 754
+    // fadd <fdst> <fsrc1> <fsrc2>
+    // fdst = fsrc1 + fsrc2
+    FADD,
+    // fsub <fdst> <fsrc1> <fsrc2>
+    // fdst = fsrc1 - fsrc2
+    FSUB,
+    // fmul <fdst> <fsrc1> <fsrc2>
+    // fdst = fsrc1 * fsrc2
+    FMUL,
+    // Dividing by zero results in an infinity or NaN instead of an error
+    // fdiv <fdst> <fsrc1> <fsrc2>
+    // fdst = fsrc1 / fsrc2
+    FDIV,
+    // Compare two float registers and set the comparison flag
+    // Every comparison with NaN is false, except FNEQ
+    // feq <freg1> <freg2>
+    FEQ,
+    // fneq <freg1> <freg2>
+    FNEQ,
+    // fgt <freg1> <freg2>
+    FGT,
+    // flt <freg1> <freg2>
+    FLT,
+    // fgte <freg1> <freg2>
+    FGTE,
+    // flte <freg1> <freg2>
+    FLTE,
+    // Convert an integer register to a float register
+    // itof <fdst> <src>
+    ITOF,
+    // Convert a float register to an integer register, rounding towards zero
+    // Values out of range saturate and NaN converts to 0
+    // ftoi <dst> <fsrc>
+    FTOI,
+    // Install a handler at the address in a register for a kind of runtime fault (see `TrapKind`)
+    // When the fault happens, the VM jumps to the handler instead of stopping
+    // trap [kind] <addr>
+    TRAP,
+    // Remove the handler for a kind of runtime fault
+    // untrap [kind]
+    UNTRAP,
+    // Enable the dispatch of interrupts queued by the host
+    // ei
+    EI,
+    // Disable the dispatch of interrupts, they stay queued until interrupts are enabled again
+    // di
+    DI,
+    // Return from an interrupt handler, restoring the flags and re-enabling interrupts
+    // reti
+    RETI,
     // Unknown opcode
     UKWN,
 }
@@ -97,34 +317,88 @@ impl From<u8> for OpCode {
             16 => OpCode::JEQ,
             17 => OpCode::JNE,
             18 => OpCode::SYS,
+            19 => OpCode::LD8,
+            20 => OpCode::LD16,
+            21 => OpCode::LD32,
+            22 => OpCode::LD64,
+            23 => OpCode::ST8,
+            24 => OpCode::ST16,
+            25 => OpCode::ST32,
+            26 => OpCode::ST64,
+            27 => OpCode::CALL,
+            28 => OpCode::RET,
+            29 => OpCode::PUSH,
+            30 => OpCode::POP,
+            31 => OpCode::JZ,
+            32 => OpCode::JNZ,
+            33 => OpCode::JLT,
+            34 => OpCode::JGE,
+            35 => OpCode::JGT,
+            36 => OpCode::JLE,
+            37 => OpCode::JC,
+            38 => OpCode::JNC,
+            39 => OpCode::JO,
+            40 => OpCode::JNO,
+            41 => OpCode::ADDT,
+            42 => OpCode::SUBT,
+            43 => OpCode::MULT,
+            44 => OpCode::ADDW,
+            45 => OpCode::SUBW,
+            46 => OpCode::MULW,
+            47 => OpCode::ADDS,
+            48 => OpCode::SUBS,
+            49 => OpCode::MULS,
+            50 => OpCode::AND,
+            51 => OpCode::OR,
+            52 => OpCode::XOR,
+            53 => OpCode::NOT,
+            54 => OpCode::SHL,
+            55 => OpCode::SHR,
+            56 => OpCode::SAR,
+            57 => OpCode::MOD,
+            58 => OpCode::NEG,
+            59 => OpCode::ABS,
+            60 => OpCode::MIN,
+            61 => OpCode::MAX,
+            62 => OpCode::INC,
+            63 => OpCode::DEC,
+            64 => OpCode::ADDI,
+            65 => OpCode::SUBI,
+            66 => OpCode::MULI,
+            67 => OpCode::EQI,
+            68 => OpCode::NEQI,
+            69 => OpCode::GTI,
+            70 => OpCode::LTI,
+            71 => OpCode::GTEI,
+            72 => OpCode::LTEI,
+            73 => OpCode::LOADW,
+            74 => OpCode::FLOAD,
+            75 => OpCode::FMOV,
+            76 => OpCode::FADD,
+            77 => OpCode::FSUB,
+            78 => OpCode::FMUL,
+            79 => OpCode::FDIV,
+            80 => OpCode::FEQ,
+            81 => OpCode::FNEQ,
+            82 => OpCode::FGT,
+            83 => OpCode::FLT,
+            84 => OpCode::FGTE,
+            85 => OpCode::FLTE,
+            86 => OpCode::ITOF,
+            87 => OpCode::FTOI,
+            88 => OpCode::TRAP,
+            89 => OpCode::UNTRAP,
+            90 => OpCode::EI,
+            91 => OpCode::DI,
+            92 => OpCode::RETI,
             _ => OpCode::UKWN,
         }
     }
 }
 
+// Mnemonics are defined along with the instructions, anything else is UKWN
 impl From<&str> for OpCode {
     fn from(s: &str) -> Self {
-        match s {
-            "stop" => OpCode::STOP,
-            "load" => OpCode::LOAD,
-            "mov" => OpCode::MOV,
-            "add" => OpCode::ADD,
-            "sub" => OpCode::SUB,
-            "mul" => OpCode::MUL,
-            "div" => OpCode::DIV,
-            "jmp" => OpCode::JMP,
-            "jmpf" => OpCode::JFW,
-            "jmpb" => OpCode::JBK,
-            "eq" => OpCode::EQ,
-            "neq" => OpCode::NEQ,
-            "gt" => OpCode::GT,
-            "lt" => OpCode::LT,
-            "gte" => OpCode::GTE,
-            "lte" => OpCode::LTE,
-            "jeq" => OpCode::JEQ,
-            "jne" => OpCode::JNE,
-            "sys" => OpCode::SYS,
-            _ => OpCode::UKWN,
-        }
+        OpCode::from_mnemonic(s).unwrap_or(OpCode::UKWN)
     }
 }
@@ -0,0 +1,504 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::line_table::LineTable;
+use crate::vm::VM;
+
+// Binary program format:
+// - header: magic (4 bytes), format version (u16), ISA version (u16), flags (u32), section count (u16)
+// - sections: kind (u8), length (u32), contents
+// - checksum: CRC-32 of everything before it (u32)
+// All integers are big-endian, like the bytecode
+pub const MAGIC: [u8; 4] = *b"RMPG";
+pub const FORMAT_VERSION: u16 = 1;
+// Bumped whenever opcode numbers or operand layouts change, as older bytecode would run incorrectly
+pub const ISA_VERSION: u16 = 2;
+
+const HEADER_SIZE: usize = 14;
+const CHECKSUM_SIZE: usize = 4;
+
+const SECTION_CODE: u8 = 1;
+const SECTION_DATA: u8 = 2;
+const SECTION_SYMBOLS: u8 = 3;
+const SECTION_DEBUG: u8 = 4;
+const SECTION_LINES: u8 = 5;
+
+#[derive(Debug)]
+pub enum ProgramError {
+    Io(std::io::Error),
+    // The file does not start with the magic number, so it is not a program
+    InvalidMagic,
+    UnsupportedFormatVersion(u16),
+    UnsupportedIsaVersion(u16),
+    // The file was corrupted after being saved
+    ChecksumMismatch { expected: u32, actual: u32 },
+    // The file ends in the middle of the header, a section or the checksum
+    Truncated,
+    MissingCodeSection,
+    DuplicateSection(u8),
+    InvalidSymbolTable,
+    InvalidLineTable,
+    // The data section does not fit in the memory of the VM it is loaded into
+    DataTooLarge { size: usize, memory: usize },
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProgramError::Io(e) => write!(f, "{}", e),
+            ProgramError::InvalidMagic => write!(f, "not a program file"),
+            ProgramError::UnsupportedFormatVersion(version) => write!(
+                f,
+                "unsupported program format version {} (expected {})",
+                version, FORMAT_VERSION
+            ),
+            ProgramError::UnsupportedIsaVersion(version) => write!(
+                f,
+                "program was built for instruction set version {} (expected {})",
+                version, ISA_VERSION
+            ),
+            ProgramError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch, the program is corrupted (expected {:08x}, got {:08x})",
+                expected, actual
+            ),
+            ProgramError::Truncated => write!(f, "program file is truncated"),
+            ProgramError::MissingCodeSection => write!(f, "program has no code section"),
+            ProgramError::DuplicateSection(kind) => write!(f, "duplicate section {}", kind),
+            ProgramError::InvalidSymbolTable => write!(f, "invalid symbol table"),
+            ProgramError::InvalidLineTable => write!(f, "invalid line table"),
+            ProgramError::DataTooLarge { size, memory } => write!(
+                f,
+                "{}-byte data section does not fit in {} bytes of memory",
+                size, memory
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+impl From<std::io::Error> for ProgramError {
+    fn from(e: std::io::Error) -> Self {
+        ProgramError::Io(e)
+    }
+}
+
+// A named address in the code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+}
+
+// Assembled bytecode along with everything needed to run it, which can be saved to and loaded from a file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    // Reserved for embedders, the VM does not interpret them
+    pub flags: u32,
+    pub code: Vec<u8>,
+    // Initial contents of memory, copied to address 0 when the program is loaded
+    pub data: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    // Opaque debug information
+    pub debug: Vec<u8>,
+    // Source locations of the code
+    pub line_table: LineTable,
+}
+
+impl Program {
+    pub fn new(code: Vec<u8>) -> Program {
+        Program {
+            code,
+            ..Program::default()
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ProgramError> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Program, ProgramError> {
+        Program::from_bytes(&std::fs::read(path)?)
+    }
+
+    // Returns true if `bytes` starts like a program file, without validating the rest
+    pub fn is_program(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    // Empty optional sections are left out
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sections = vec![(SECTION_CODE, self.code.clone())];
+        if !self.data.is_empty() {
+            sections.push((SECTION_DATA, self.data.clone()));
+        }
+        if !self.symbols.is_empty() {
+            sections.push((SECTION_SYMBOLS, encode_symbols(&self.symbols)));
+        }
+        if !self.debug.is_empty() {
+            sections.push((SECTION_DEBUG, self.debug.clone()));
+        }
+        if !self.line_table.is_empty() {
+            sections.push((SECTION_LINES, encode_line_table(&self.line_table)));
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&ISA_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&self.flags.to_be_bytes());
+        bytes.extend_from_slice(&(sections.len() as u16).to_be_bytes());
+        for (kind, contents) in sections {
+            bytes.push(kind);
+            bytes.extend_from_slice(&(contents.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&contents);
+        }
+
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    // Unknown section kinds are skipped, so newer writers can add sections
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, ProgramError> {
+        if !Program::is_program(bytes) {
+            return Err(ProgramError::InvalidMagic);
+        }
+        if bytes.len() < HEADER_SIZE + CHECKSUM_SIZE {
+            return Err(ProgramError::Truncated);
+        }
+
+        let mut reader = Reader {
+            bytes: &bytes[..bytes.len() - CHECKSUM_SIZE],
+            offset: MAGIC.len(),
+        };
+
+        // Versions are checked first, a different format may not have its checksum in the same place
+        let format_version = reader.u16()?;
+        if format_version != FORMAT_VERSION {
+            return Err(ProgramError::UnsupportedFormatVersion(format_version));
+        }
+
+        let expected = u32::from_be_bytes(bytes[bytes.len() - CHECKSUM_SIZE..].try_into().unwrap());
+        let actual = crc32(reader.bytes);
+        if expected != actual {
+            return Err(ProgramError::ChecksumMismatch { expected, actual });
+        }
+
+        let isa_version = reader.u16()?;
+        if isa_version != ISA_VERSION {
+            return Err(ProgramError::UnsupportedIsaVersion(isa_version));
+        }
+
+        let mut program = Program {
+            flags: reader.u32()?,
+            ..Program::default()
+        };
+        let mut seen = Vec::new();
+        for _ in 0..reader.u16()? {
+            let kind = reader.u8()?;
+            let length = reader.u32()? as usize;
+            let contents = reader.take(length)?;
+
+            if seen.contains(&kind) {
+                return Err(ProgramError::DuplicateSection(kind));
+            }
+            seen.push(kind);
+
+            match kind {
+                SECTION_CODE => program.code = contents.to_vec(),
+                SECTION_DATA => program.data = contents.to_vec(),
+                SECTION_SYMBOLS => program.symbols = decode_symbols(contents)?,
+                SECTION_DEBUG => program.debug = contents.to_vec(),
+                SECTION_LINES => program.line_table = decode_line_table(contents)?,
+                _ => {}
+            }
+        }
+
+        if !seen.contains(&SECTION_CODE) {
+            return Err(ProgramError::MissingCodeSection);
+        }
+
+        Ok(program)
+    }
+
+    // Replaces the VM's code and line table with the program's and resets the pc,
+    // then copies the data section to memory
+    pub fn load_into<H>(&self, vm: &mut VM<H>) -> Result<(), ProgramError> {
+        let memory = vm.memory.len();
+        if self.data.len() > memory {
+            return Err(ProgramError::DataTooLarge {
+                size: self.data.len(),
+                memory,
+            });
+        }
+
+        vm.code = self.code.clone();
+        vm.line_table = self.line_table.clone();
+        vm.pc = 0;
+        vm.memory[..self.data.len()].copy_from_slice(&self.data);
+        Ok(())
+    }
+}
+
+// Symbol table: count (u32), then for each symbol its address (u32), name length (u16) and UTF-8 name
+fn encode_symbols(symbols: &[Symbol]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(symbols.len() as u32).to_be_bytes());
+    for symbol in symbols {
+        bytes.extend_from_slice(&symbol.address.to_be_bytes());
+        bytes.extend_from_slice(&(symbol.name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(symbol.name.as_bytes());
+    }
+    bytes
+}
+
+fn decode_symbols(bytes: &[u8]) -> Result<Vec<Symbol>, ProgramError> {
+    let mut reader = Reader { bytes, offset: 0 };
+    let invalid = |_| ProgramError::InvalidSymbolTable;
+
+    let count = reader.u32().map_err(invalid)?;
+    let mut symbols = Vec::new();
+    for _ in 0..count {
+        let address = reader.u32().map_err(invalid)?;
+        let length = reader.u16().map_err(invalid)? as usize;
+        let name = reader.take(length).map_err(invalid)?;
+        let name =
+            String::from_utf8(name.to_vec()).map_err(|_| ProgramError::InvalidSymbolTable)?;
+        symbols.push(Symbol { name, address });
+    }
+
+    if reader.offset != bytes.len() {
+        return Err(ProgramError::InvalidSymbolTable);
+    }
+
+    Ok(symbols)
+}
+
+// Line table: file count (u16), then for each file its name length (u16) and UTF-8 name,
+// followed by the entry count (u32), then for each entry its start and end addresses (u32),
+// file index (u16), line (u32) and column (u32)
+fn encode_line_table(table: &LineTable) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(table.files().len() as u16).to_be_bytes());
+    for file in table.files() {
+        bytes.extend_from_slice(&(file.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(file.as_bytes());
+    }
+    bytes.extend_from_slice(&(table.entries().len() as u32).to_be_bytes());
+    for entry in table.entries() {
+        bytes.extend_from_slice(&(entry.start as u32).to_be_bytes());
+        bytes.extend_from_slice(&(entry.end as u32).to_be_bytes());
+        bytes.extend_from_slice(&(entry.file as u16).to_be_bytes());
+        bytes.extend_from_slice(&(entry.line as u32).to_be_bytes());
+        bytes.extend_from_slice(&(entry.column as u32).to_be_bytes());
+    }
+    bytes
+}
+
+fn decode_line_table(bytes: &[u8]) -> Result<LineTable, ProgramError> {
+    let mut reader = Reader { bytes, offset: 0 };
+    let invalid = |_| ProgramError::InvalidLineTable;
+
+    let mut files = Vec::new();
+    for _ in 0..reader.u16().map_err(invalid)? {
+        let length = reader.u16().map_err(invalid)? as usize;
+        let name = reader.take(length).map_err(invalid)?;
+        files.push(String::from_utf8(name.to_vec()).map_err(|_| ProgramError::InvalidLineTable)?);
+    }
+
+    let mut table = LineTable::new();
+    for _ in 0..reader.u32().map_err(invalid)? {
+        let start = reader.u32().map_err(invalid)? as usize;
+        let end = reader.u32().map_err(invalid)? as usize;
+        let file = reader.u16().map_err(invalid)? as usize;
+        let line = reader.u32().map_err(invalid)? as usize;
+        let column = reader.u32().map_err(invalid)? as usize;
+
+        let file = files.get(file).ok_or(ProgramError::InvalidLineTable)?;
+        if !table.add(file, start..end, line, column) {
+            return Err(ProgramError::InvalidLineTable);
+        }
+    }
+
+    if reader.offset != bytes.len() {
+        return Err(ProgramError::InvalidLineTable);
+    }
+
+    Ok(table)
+}
+
+// Reads big-endian values from a byte slice, failing with `ProgramError::Truncated` at the end
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProgramError> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset.saturating_add(len))
+            .ok_or(ProgramError::Truncated)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProgramError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProgramError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProgramError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+// CRC-32 (IEEE 802.3), as used by zlib and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod program_tests {
+    use super::*;
+    use crate::assembler::assemble_file;
+
+    fn program() -> Program {
+        let input = String::from("load %0 #8\nld8 %1 %0\n");
+        let vm = assemble_file("data.rm", input, VM::new()).unwrap();
+        Program {
+            flags: 7,
+            code: vm.code,
+            data: vec![0, 0, 0, 0, 0, 0, 0, 0, 42],
+            symbols: vec![Symbol {
+                name: String::from("main"),
+                address: 0,
+            }],
+            debug: vec![1, 2, 3],
+            line_table: vm.line_table,
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = program();
+        assert_eq!(Program::from_bytes(&program.to_bytes()).unwrap(), program);
+
+        let program = Program::new(vec![0]);
+        assert_eq!(Program::from_bytes(&program.to_bytes()).unwrap(), program);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("program-{}.rmb", std::process::id()));
+        let program = program();
+        program.save(&path).unwrap();
+        let loaded = Program::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut vm = VM::new();
+        loaded.unwrap().load_into(&mut vm).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[1], 42);
+        assert_eq!(vm.source_location(4).unwrap().to_string(), "data.rm:2:1");
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let bytes = program().to_bytes();
+
+        assert!(matches!(
+            Program::from_bytes(b"#!/bin/sh"),
+            Err(ProgramError::InvalidMagic)
+        ));
+        assert!(matches!(
+            Program::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ProgramError::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(
+            Program::from_bytes(&bytes[..10]),
+            Err(ProgramError::Truncated)
+        ));
+
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_SIZE + 5] ^= 1;
+        assert!(matches!(
+            Program::from_bytes(&corrupted),
+            Err(ProgramError::ChecksumMismatch { .. })
+        ));
+
+        let mut format = bytes.clone();
+        format[5] = 2;
+        assert!(matches!(
+            Program::from_bytes(&format),
+            Err(ProgramError::UnsupportedFormatVersion(2))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_line_table() {
+        let table = program().line_table;
+        let bytes = encode_line_table(&table);
+        assert_eq!(decode_line_table(&bytes).unwrap(), table);
+
+        // Truncated, and an entry referring to a file that does not exist
+        assert!(matches!(
+            decode_line_table(&bytes[..bytes.len() - 1]),
+            Err(ProgramError::InvalidLineTable)
+        ));
+        assert!(matches!(
+            decode_line_table(&[
+                0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1
+            ]),
+            Err(ProgramError::InvalidLineTable)
+        ));
+    }
+
+    #[test]
+    fn test_isa_version() {
+        let mut bytes = program().to_bytes();
+        bytes.truncate(bytes.len() - CHECKSUM_SIZE);
+        bytes[7] = 9;
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+
+        let error = Program::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(error, ProgramError::UnsupportedIsaVersion(9)));
+        assert_eq!(
+            error.to_string(),
+            "program was built for instruction set version 9 (expected 2)"
+        );
+    }
+
+    #[test]
+    fn test_data_too_large() {
+        let mut vm = VM::new();
+        vm.memory = vec![0; 4];
+
+        assert!(matches!(
+            program().load_into(&mut vm),
+            Err(ProgramError::DataTooLarge { size: 9, memory: 4 })
+        ));
+    }
+}
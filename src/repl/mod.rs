@@ -6,15 +6,12 @@ pub fn start_repl() {
     let mut vm = VM::new();
 
     // Print syscall
-    // Print the value a register
-    // Register to print stored at %80
-    vm.register_syscall(0, |vm| {
-        let register = vm.registers[80];
-        let value = vm.registers[register as usize];
-
+    // Prints the value of its first argument, %1
+    vm.register_syscall_with_args(0, 1, |args| {
+        let value: i64 = args.get(0)?;
         println!("{}", value);
 
-        true
+        Ok(())
     });
 
     loop {
@@ -25,7 +22,12 @@ pub fn start_repl() {
 
         if let Ok(new_vm) = assemble(input, vm.clone()) {
             vm = new_vm;
-            vm.run();
+
+            if let Err(e) = vm.run() {
+                println!("Error: {}", e);
+                // Skip past the faulting code so the next line starts from a clean state
+                vm.pc = vm.code.len();
+            }
 
             continue;
         }
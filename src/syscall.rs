@@ -0,0 +1,314 @@
+use std::fmt;
+
+use crate::vm::VM;
+
+// Syscall calling convention:
+// - arguments are passed in %1 to %6, in order, and float arguments in $1 to $6
+// - the result is returned in %0, or $0 for a float result
+// - %7 is the error flag, set to 0 on success and to an error code on failure
+// Registers outside of %0 to %7 are preserved by syscalls registered through `VM::register_syscall_with_args`
+pub const FIRST_ARGUMENT_REGISTER: u8 = 1;
+pub const MAX_ARGUMENTS: usize = 6;
+pub const RETURN_REGISTER: u8 = 0;
+pub const ERROR_REGISTER: u8 = 7;
+
+// Error code written to the error register when an argument has the wrong type or range
+pub const ERROR_INVALID_ARGUMENT: i64 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyscallError {
+    // The syscall read an argument beyond the arity it was registered with
+    // This is a bug in the host, so it fails the SYS instruction instead of being reported to the guest
+    Arity { arity: usize, index: usize },
+    // The value of an argument does not fit the requested type
+    InvalidArgument { index: usize, value: i64 },
+    // The syscall failed with a guest-visible error code
+    Failed(i64),
+}
+
+impl SyscallError {
+    // The code written to the error register
+    pub fn code(&self) -> i64 {
+        match self {
+            SyscallError::Arity { .. } | SyscallError::InvalidArgument { .. } => {
+                ERROR_INVALID_ARGUMENT
+            }
+            SyscallError::Failed(code) => *code,
+        }
+    }
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyscallError::Arity { arity, index } => write!(
+                f,
+                "argument {} read by a syscall taking {} arguments",
+                index, arity
+            ),
+            SyscallError::InvalidArgument { index, value } => {
+                write!(f, "invalid value {} for argument {}", value, index)
+            }
+            SyscallError::Failed(code) => write!(f, "syscall failed with code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for SyscallError {}
+
+// A type that can be read from a syscall argument register
+pub trait FromRegister: Sized {
+    fn from_register(value: i64) -> Option<Self>;
+}
+
+// A type that can be written to the syscall return register
+pub trait IntoRegister {
+    fn into_register(self) -> i64;
+}
+
+impl FromRegister for i64 {
+    fn from_register(value: i64) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl FromRegister for bool {
+    fn from_register(value: i64) -> Option<Self> {
+        match value {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl IntoRegister for i64 {
+    fn into_register(self) -> i64 {
+        self
+    }
+}
+
+impl IntoRegister for bool {
+    fn into_register(self) -> i64 {
+        self as i64
+    }
+}
+
+macro_rules! register_conversions {
+    ($($t:ty),*) => {
+        $(
+            impl FromRegister for $t {
+                fn from_register(value: i64) -> Option<Self> {
+                    <$t>::try_from(value).ok()
+                }
+            }
+
+            // Values too large for an i64 saturate at i64::MAX
+            impl IntoRegister for $t {
+                fn into_register(self) -> i64 {
+                    i64::try_from(self).unwrap_or(i64::MAX)
+                }
+            }
+        )*
+    };
+}
+
+register_conversions!(u8, u16, u32, u64, usize, i8, i16, i32);
+
+// Typed access to the arguments and result of a syscall following the calling convention above
+pub struct SyscallArgs<'a, H> {
+    vm: &'a mut VM<H>,
+    host: &'a mut H,
+    arity: usize,
+}
+
+impl<'a, H> SyscallArgs<'a, H> {
+    pub fn new(vm: &'a mut VM<H>, host: &'a mut H, arity: usize) -> Self {
+        Self { vm, host, arity }
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    // Reads argument `index` (starting at 0) converted to `T`
+    pub fn get<T: FromRegister>(&self, index: usize) -> Result<T, SyscallError> {
+        if index >= self.arity {
+            return Err(SyscallError::Arity {
+                arity: self.arity,
+                index,
+            });
+        }
+
+        let value = self.vm.registers[FIRST_ARGUMENT_REGISTER as usize + index];
+        T::from_register(value).ok_or(SyscallError::InvalidArgument { index, value })
+    }
+
+    // Reads float argument `index` (starting at 0) from the float registers
+    pub fn get_float(&self, index: usize) -> Result<f64, SyscallError> {
+        if index >= self.arity {
+            return Err(SyscallError::Arity {
+                arity: self.arity,
+                index,
+            });
+        }
+
+        Ok(self.vm.float_registers[FIRST_ARGUMENT_REGISTER as usize + index])
+    }
+
+    pub fn set_return<T: IntoRegister>(&mut self, value: T) {
+        self.vm.registers[RETURN_REGISTER as usize] = value.into_register();
+    }
+
+    pub fn set_float_return(&mut self, value: f64) {
+        self.vm.float_registers[RETURN_REGISTER as usize] = value;
+    }
+
+    pub fn vm(&mut self) -> &mut VM<H> {
+        self.vm
+    }
+
+    pub fn host(&mut self) -> &mut H {
+        self.host
+    }
+}
+
+impl<H> VM<H> {
+    // Registers a syscall taking `arity` arguments through the calling convention
+    // On success the error register is cleared, on failure it is set to the error's code and execution continues,
+    // except for arity errors which fail the SYS instruction
+    pub fn register_syscall_with_args(
+        &mut self,
+        id: u16,
+        arity: usize,
+        mut syscall: impl FnMut(&mut SyscallArgs<H>) -> Result<(), SyscallError> + Send + 'static,
+    ) {
+        assert!(
+            arity <= MAX_ARGUMENTS,
+            "syscalls take at most {} arguments",
+            MAX_ARGUMENTS
+        );
+
+        self.register_host_syscall(id, move |vm, host| {
+            let result = syscall(&mut SyscallArgs::new(vm, host, arity));
+
+            vm.registers[ERROR_REGISTER as usize] = match result {
+                Ok(()) => 0,
+                Err(SyscallError::Arity { .. }) => return false,
+                Err(e) => e.code(),
+            };
+
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod syscall_tests {
+    use super::*;
+    use crate::error::VmError;
+    use crate::opcode::OpCode;
+
+    fn call(vm: &mut VM, id: u16, args: &[i64]) -> Result<(), VmError> {
+        for (i, arg) in args.iter().enumerate() {
+            vm.registers[FIRST_ARGUMENT_REGISTER as usize + i] = *arg;
+        }
+
+        vm.code.clear();
+        vm.pc = 0;
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(id);
+        vm.run().map(|_| ())
+    }
+
+    #[test]
+    fn test_typed_arguments() {
+        let mut vm = VM::new();
+        vm.register_syscall_with_args(0, 2, |args| {
+            let a: i64 = args.get(0)?;
+            let b: u8 = args.get(1)?;
+            args.set_return(a * b as i64);
+
+            Ok(())
+        });
+
+        call(&mut vm, 0, &[-4, 3]).unwrap();
+        assert_eq!(vm.registers[RETURN_REGISTER as usize], -12);
+        assert_eq!(vm.registers[ERROR_REGISTER as usize], 0);
+
+        // 300 does not fit in a u8
+        call(&mut vm, 0, &[1, 300]).unwrap();
+        assert_eq!(vm.registers[RETURN_REGISTER as usize], -12);
+        assert_eq!(
+            vm.registers[ERROR_REGISTER as usize],
+            ERROR_INVALID_ARGUMENT
+        );
+    }
+
+    #[test]
+    fn test_failure_code() {
+        let mut vm = VM::new();
+        vm.register_syscall_with_args(0, 1, |args| {
+            if args.get::<bool>(0)? {
+                Err(SyscallError::Failed(42))
+            } else {
+                Ok(())
+            }
+        });
+
+        call(&mut vm, 0, &[1]).unwrap();
+        assert_eq!(vm.registers[ERROR_REGISTER as usize], 42);
+        call(&mut vm, 0, &[0]).unwrap();
+        assert_eq!(vm.registers[ERROR_REGISTER as usize], 0);
+    }
+
+    #[test]
+    fn test_arity_error() {
+        let mut vm = VM::new();
+        vm.register_syscall_with_args(0, 1, |args| {
+            let _: i64 = args.get(1)?;
+
+            Ok(())
+        });
+
+        assert_eq!(
+            call(&mut vm, 0, &[1, 2]),
+            Err(VmError::SyscallFailed { id: 0, pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_host_access() {
+        let mut vm = VM::with_host(Vec::<i64>::new());
+        vm.register_syscall_with_args(0, 1, |args| {
+            let value = args.get(0)?;
+            args.host().push(value);
+
+            Ok(())
+        });
+
+        vm.registers[1] = 9;
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+        vm.run().unwrap();
+
+        assert_eq!(vm.host(), &vec![9]);
+    }
+
+    #[test]
+    fn test_float_arguments() {
+        let mut vm = VM::new();
+        vm.register_syscall_with_args(0, 2, |args| {
+            let a = args.get_float(0)?;
+            let b: i64 = args.get(1)?;
+            args.set_float_return(a.powi(b as i32));
+
+            Ok(())
+        });
+
+        vm.float_registers[1] = 1.5;
+        call(&mut vm, 0, &[0, 2]).unwrap();
+        assert_eq!(vm.float_registers[RETURN_REGISTER as usize], 2.25);
+        assert_eq!(vm.registers[ERROR_REGISTER as usize], 0);
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use crate::error::{TrapKind, VmError};
+use crate::instruction::Instruction;
+use crate::vm::VM;
+
+// Problems found in bytecode before running it
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifyError {
+    UnknownOpcode { pc: usize, byte: u8 },
+    // The code ends before all operands of the instruction at pc
+    TruncatedInstruction { pc: usize },
+    UnknownSyscall { pc: usize, id: u16 },
+    InvalidTrapKind { pc: usize, kind: u16 },
+    // A jump, call or trap handler whose target is known before running the code
+    JumpOutOfBounds { pc: usize, target: i64 },
+    JumpIntoInstruction { pc: usize, target: usize },
+    // A jump whose target is in the data following the code
+    JumpIntoData { pc: usize, target: usize },
+}
+
+impl VerifyError {
+    pub fn pc(&self) -> usize {
+        match *self {
+            VerifyError::UnknownOpcode { pc, .. }
+            | VerifyError::TruncatedInstruction { pc }
+            | VerifyError::UnknownSyscall { pc, .. }
+            | VerifyError::InvalidTrapKind { pc, .. }
+            | VerifyError::JumpOutOfBounds { pc, .. }
+            | VerifyError::JumpIntoInstruction { pc, .. }
+            | VerifyError::JumpIntoData { pc, .. } => pc,
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::UnknownOpcode { pc, byte } => {
+                write!(f, "unknown opcode {} at pc {}", byte, pc)
+            }
+            VerifyError::TruncatedInstruction { pc } => {
+                write!(f, "truncated instruction at pc {}", pc)
+            }
+            VerifyError::UnknownSyscall { pc, id } => {
+                write!(f, "unknown syscall {} at pc {}", id, pc)
+            }
+            VerifyError::InvalidTrapKind { pc, kind } => {
+                write!(f, "invalid trap kind {} at pc {}", kind, pc)
+            }
+            VerifyError::JumpOutOfBounds { pc, target } => {
+                write!(f, "jump to {} out of bounds at pc {}", target, pc)
+            }
+            VerifyError::JumpIntoInstruction { pc, target } => write!(
+                f,
+                "jump to {} lands in the middle of an instruction at pc {}",
+                target, pc
+            ),
+            VerifyError::JumpIntoData { pc, target } => write!(
+                f,
+                "jump to {} lands in data after the end of the code at pc {}",
+                target, pc
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// The value of every register if it is known to be a constant, None otherwise
+type Constants = Box<[Option<i64>; 256]>;
+
+fn unknown() -> Constants {
+    Box::new([None; 256])
+}
+
+// Where control can go after an instruction, with the target of jumps if it is known
+struct Successors {
+    fallthrough: bool,
+    target: Option<i64>,
+    // Calls and syscalls may change any register
+    clobbers: bool,
+}
+
+impl<H> VM<H> {
+    // Checks the code for errors that would stop the VM, so untrusted bytecode can be rejected up front
+    // Syscalls must be registered before verifying, and all errors are returned, ordered by pc
+    //
+    // Jump targets are checked when the register holding them has a known value, which is tracked
+    // through LOAD, LOADW and MOV along the paths the verifier can follow from pc 0, interrupt vectors
+    // and trap handlers. Registers are assumed to be preserved by interrupt handlers
+    //
+    // Once an instruction without fall-through (STOP, RET, RETI or an unconditional jump) has been seen,
+    // the first bytes that do not decode are taken as data, such as `.byte` values placed after the
+    // code, and end the code. Code following that data is not verified, and jumps into it are reported,
+    // as are reachable instructions that fall through into it
+    pub fn verify(&self) -> Result<(), Vec<VerifyError>> {
+        let mut errors = BTreeSet::new();
+
+        // Decode the code from start to end to find instruction boundaries
+        let mut instructions = HashMap::new();
+        let mut boundaries = HashSet::new();
+        let mut code_end = self.code.len();
+        let mut can_end = false;
+        let mut pc = 0;
+        while pc < self.code.len() {
+            match Instruction::decode(&self.code, pc) {
+                Ok((instruction, len)) => {
+                    boundaries.insert(pc);
+                    instructions.insert(pc, (instruction, len));
+                    can_end |= !successors(&instruction, pc + len, &unknown()).fallthrough;
+                    pc += len;
+                }
+                Err(_) if can_end => {
+                    code_end = pc;
+                    break;
+                }
+                Err(VmError::UnknownOpcode { byte, .. }) => {
+                    boundaries.insert(pc);
+                    errors.insert(VerifyError::UnknownOpcode { pc, byte });
+                    pc += 1;
+                }
+                Err(_) => {
+                    errors.insert(VerifyError::TruncatedInstruction { pc });
+                    break;
+                }
+            }
+        }
+
+        for (&pc, (instruction, _)) in &instructions {
+            match *instruction {
+                Instruction::Sys { id } if !self.syscalls.contains_key(&id) => {
+                    errors.insert(VerifyError::UnknownSyscall { pc, id });
+                }
+                Instruction::Trap { kind, .. } | Instruction::Untrap { kind }
+                    if TrapKind::from_code(kind).is_none() =>
+                {
+                    errors.insert(VerifyError::InvalidTrapKind { pc, kind });
+                }
+                _ => {}
+            }
+        }
+
+        // Propagate known register values along the control flow until they stop changing
+        let mut states: HashMap<usize, Constants> = HashMap::new();
+        let mut worklist = vec![(0, unknown())];
+        let entry_points = (0..=u8::MAX)
+            .filter_map(|interrupt| self.interrupt_vector(interrupt))
+            .chain(
+                (1..)
+                    .map_while(TrapKind::from_code)
+                    .filter_map(|kind| self.trap_handler(kind)),
+            );
+        worklist.extend(entry_points.map(|pc| (pc, unknown())));
+
+        while let Some((pc, incoming)) = worklist.pop() {
+            let Some(&(instruction, len)) = instructions.get(&pc) else {
+                continue;
+            };
+
+            let state = match states.get_mut(&pc) {
+                Some(state) => {
+                    let mut changed = false;
+                    for (known, value) in state.iter_mut().zip(incoming.iter()) {
+                        if known.is_some() && known != value {
+                            *known = None;
+                            changed = true;
+                        }
+                    }
+                    if !changed {
+                        continue;
+                    }
+                    state.clone()
+                }
+                None => {
+                    states.insert(pc, incoming.clone());
+                    incoming
+                }
+            };
+
+            let successors = successors(&instruction, pc + len, &state);
+            let next = if successors.clobbers {
+                unknown()
+            } else {
+                propagate(&instruction, state)
+            };
+
+            if let Some(target) = successors.target {
+                if let Ok(target) = usize::try_from(target) {
+                    // Trap handlers run after a fault, when any register may have changed
+                    let entry = match instruction {
+                        Instruction::Trap { .. } => unknown(),
+                        _ => next.clone(),
+                    };
+                    worklist.push((target, entry));
+                }
+            }
+            if successors.fallthrough {
+                if pc + len == code_end && code_end < self.code.len() {
+                    // Execution would run off the end of the code into the data after it
+                    let error = match Instruction::decode(&self.code, code_end) {
+                        Err(VmError::UnknownOpcode { byte, .. }) => {
+                            VerifyError::UnknownOpcode { pc: code_end, byte }
+                        }
+                        _ => VerifyError::TruncatedInstruction { pc: code_end },
+                    };
+                    errors.insert(error);
+                } else {
+                    worklist.push((pc + len, next));
+                }
+            }
+        }
+
+        for (&pc, state) in &states {
+            let (instruction, len) = instructions[&pc];
+            let Some(target) = successors(&instruction, pc + len, state).target else {
+                continue;
+            };
+
+            match usize::try_from(target) {
+                Ok(target) if target == self.code.len() => {}
+                Ok(target) if target >= code_end && target < self.code.len() => {
+                    errors.insert(VerifyError::JumpIntoData { pc, target });
+                }
+                Ok(target) if target < self.code.len() => {
+                    if !boundaries.contains(&target) {
+                        errors.insert(VerifyError::JumpIntoInstruction { pc, target });
+                    }
+                }
+                _ => {
+                    errors.insert(VerifyError::JumpOutOfBounds { pc, target });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            let mut errors: Vec<VerifyError> = errors.into_iter().collect();
+            errors.sort_by_key(VerifyError::pc);
+            Err(errors)
+        }
+    }
+}
+
+// `next` is the address of the following instruction
+fn successors(instruction: &Instruction, next: usize, state: &Constants) -> Successors {
+    let constant = |register: u8| state[register as usize];
+    let (fallthrough, target, clobbers) = match *instruction {
+        Instruction::Stop {} | Instruction::Ret {} | Instruction::Reti {} => (false, None, false),
+        Instruction::Jmp { addr } => (false, constant(addr), false),
+        Instruction::Jfw { offset } => (
+            false,
+            constant(offset).map(|offset| (next as i64).saturating_add(offset)),
+            false,
+        ),
+        Instruction::Jbk { offset } => (
+            false,
+            constant(offset).map(|offset| (next as i64).saturating_sub(offset)),
+            false,
+        ),
+        Instruction::Jeq { addr }
+        | Instruction::Jne { addr }
+        | Instruction::Jz { addr }
+        | Instruction::Jnz { addr }
+        | Instruction::Jlt { addr }
+        | Instruction::Jge { addr }
+        | Instruction::Jgt { addr }
+        | Instruction::Jle { addr }
+        | Instruction::Jc { addr }
+        | Instruction::Jnc { addr }
+        | Instruction::Jo { addr }
+        | Instruction::Jno { addr } => (true, constant(addr), false),
+        Instruction::Call { addr } => (true, constant(addr), true),
+        Instruction::Trap { addr, .. } => (true, constant(addr), false),
+        Instruction::Sys { .. } => (true, None, true),
+        // Listed explicitly, so a new control flow instruction cannot be missed
+        Instruction::Load { .. }
+        | Instruction::Mov { .. }
+        | Instruction::Add { .. }
+        | Instruction::Sub { .. }
+        | Instruction::Mul { .. }
+        | Instruction::Div { .. }
+        | Instruction::Eq { .. }
+        | Instruction::Neq { .. }
+        | Instruction::Gt { .. }
+        | Instruction::Lt { .. }
+        | Instruction::Gte { .. }
+        | Instruction::Lte { .. }
+        | Instruction::Ld8 { .. }
+        | Instruction::Ld16 { .. }
+        | Instruction::Ld32 { .. }
+        | Instruction::Ld64 { .. }
+        | Instruction::St8 { .. }
+        | Instruction::St16 { .. }
+        | Instruction::St32 { .. }
+        | Instruction::St64 { .. }
+        | Instruction::Push { .. }
+        | Instruction::Pop { .. }
+        | Instruction::Addt { .. }
+        | Instruction::Subt { .. }
+        | Instruction::Mult { .. }
+        | Instruction::Addw { .. }
+        | Instruction::Subw { .. }
+        | Instruction::Mulw { .. }
+        | Instruction::Adds { .. }
+        | Instruction::Subs { .. }
+        | Instruction::Muls { .. }
+        | Instruction::And { .. }
+        | Instruction::Or { .. }
+        | Instruction::Xor { .. }
+        | Instruction::Not { .. }
+        | Instruction::Shl { .. }
+        | Instruction::Shr { .. }
+        | Instruction::Sar { .. }
+        | Instruction::Mod { .. }
+        | Instruction::Neg { .. }
+        | Instruction::Abs { .. }
+        | Instruction::Min { .. }
+        | Instruction::Max { .. }
+        | Instruction::Inc { .. }
+        | Instruction::Dec { .. }
+        | Instruction::Addi { .. }
+        | Instruction::Subi { .. }
+        | Instruction::Muli { .. }
+        | Instruction::Eqi { .. }
+        | Instruction::Neqi { .. }
+        | Instruction::Gti { .. }
+        | Instruction::Lti { .. }
+        | Instruction::Gtei { .. }
+        | Instruction::Ltei { .. }
+        | Instruction::Loadw { .. }
+        | Instruction::Fload { .. }
+        | Instruction::Fmov { .. }
+        | Instruction::Fadd { .. }
+        | Instruction::Fsub { .. }
+        | Instruction::Fmul { .. }
+        | Instruction::Fdiv { .. }
+        | Instruction::Feq { .. }
+        | Instruction::Fneq { .. }
+        | Instruction::Fgt { .. }
+        | Instruction::Flt { .. }
+        | Instruction::Fgte { .. }
+        | Instruction::Flte { .. }
+        | Instruction::Itof { .. }
+        | Instruction::Ftoi { .. }
+        | Instruction::Untrap { .. }
+        | Instruction::Ei {}
+        | Instruction::Di {} => (true, None, false),
+    };
+
+    Successors {
+        fallthrough,
+        target,
+        clobbers,
+    }
+}
+
+// The known register values after executing `instruction`
+fn propagate(instruction: &Instruction, mut state: Constants) -> Constants {
+    match *instruction {
+        Instruction::Load { dst, imm } => state[dst as usize] = Some(imm as i64),
+        Instruction::Loadw { dst, imm } => state[dst as usize] = Some(imm),
+        Instruction::Mov { dst, src } => state[dst as usize] = state[src as usize],
+        Instruction::Add { dst, .. }
+        | Instruction::Sub { dst, .. }
+        | Instruction::Mul { dst, .. }
+        | Instruction::Div { dst, .. }
+        | Instruction::Ld8 { dst, .. }
+        | Instruction::Ld16 { dst, .. }
+        | Instruction::Ld32 { dst, .. }
+        | Instruction::Ld64 { dst, .. }
+        | Instruction::Pop { dst }
+        | Instruction::Addt { dst, .. }
+        | Instruction::Subt { dst, .. }
+        | Instruction::Mult { dst, .. }
+        | Instruction::Addw { dst, .. }
+        | Instruction::Subw { dst, .. }
+        | Instruction::Mulw { dst, .. }
+        | Instruction::Adds { dst, .. }
+        | Instruction::Subs { dst, .. }
+        | Instruction::Muls { dst, .. }
+        | Instruction::And { dst, .. }
+        | Instruction::Or { dst, .. }
+        | Instruction::Xor { dst, .. }
+        | Instruction::Not { dst, .. }
+        | Instruction::Shl { dst, .. }
+        | Instruction::Shr { dst, .. }
+        | Instruction::Sar { dst, .. }
+        | Instruction::Mod { dst, .. }
+        | Instruction::Neg { dst, .. }
+        | Instruction::Abs { dst, .. }
+        | Instruction::Min { dst, .. }
+        | Instruction::Max { dst, .. }
+        | Instruction::Addi { dst, .. }
+        | Instruction::Subi { dst, .. }
+        | Instruction::Muli { dst, .. }
+        | Instruction::Ftoi { dst, .. }
+        | Instruction::Inc { reg: dst }
+        | Instruction::Dec { reg: dst } => state[dst as usize] = None,
+        // Listed explicitly, so a new instruction writing a register cannot leave a stale constant
+        Instruction::Stop {}
+        | Instruction::Jmp { .. }
+        | Instruction::Jfw { .. }
+        | Instruction::Jbk { .. }
+        | Instruction::Eq { .. }
+        | Instruction::Neq { .. }
+        | Instruction::Gt { .. }
+        | Instruction::Lt { .. }
+        | Instruction::Gte { .. }
+        | Instruction::Lte { .. }
+        | Instruction::Jeq { .. }
+        | Instruction::Jne { .. }
+        | Instruction::Sys { .. }
+        | Instruction::St8 { .. }
+        | Instruction::St16 { .. }
+        | Instruction::St32 { .. }
+        | Instruction::St64 { .. }
+        | Instruction::Call { .. }
+        | Instruction::Ret {}
+        | Instruction::Push { .. }
+        | Instruction::Jz { .. }
+        | Instruction::Jnz { .. }
+        | Instruction::Jlt { .. }
+        | Instruction::Jge { .. }
+        | Instruction::Jgt { .. }
+        | Instruction::Jle { .. }
+        | Instruction::Jc { .. }
+        | Instruction::Jnc { .. }
+        | Instruction::Jo { .. }
+        | Instruction::Jno { .. }
+        | Instruction::Eqi { .. }
+        | Instruction::Neqi { .. }
+        | Instruction::Gti { .. }
+        | Instruction::Lti { .. }
+        | Instruction::Gtei { .. }
+        | Instruction::Ltei { .. }
+        | Instruction::Fload { .. }
+        | Instruction::Fmov { .. }
+        | Instruction::Fadd { .. }
+        | Instruction::Fsub { .. }
+        | Instruction::Fmul { .. }
+        | Instruction::Fdiv { .. }
+        | Instruction::Feq { .. }
+        | Instruction::Fneq { .. }
+        | Instruction::Fgt { .. }
+        | Instruction::Flt { .. }
+        | Instruction::Fgte { .. }
+        | Instruction::Flte { .. }
+        | Instruction::Itof { .. }
+        | Instruction::Trap { .. }
+        | Instruction::Untrap { .. }
+        | Instruction::Ei {}
+        | Instruction::Di {}
+        | Instruction::Reti {} => {}
+    }
+
+    state
+}
+
+#[cfg(test)]
+mod verifier_tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::opcode::OpCode;
+
+    fn verify(input: &str) -> Result<(), Vec<VerifyError>> {
+        let mut vm = assemble(String::from(input), VM::new()).unwrap();
+        vm.register_syscall(0, |_| true);
+        vm.verify()
+    }
+
+    #[test]
+    fn test_examples() {
+        for example in ["examples/count.rm", "examples/factorial.rm"] {
+            let input = std::fs::read_to_string(example).unwrap();
+            assert_eq!(verify(&input), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_malformed_code() {
+        let mut vm = VM::new();
+        vm.write_u8(255); // 0
+        vm.write_opcode(OpCode::LOAD); // 1
+        vm.write_u8(1); // 2
+
+        assert_eq!(
+            vm.verify(),
+            Err(vec![
+                VerifyError::UnknownOpcode { pc: 0, byte: 255 },
+                VerifyError::TruncatedInstruction { pc: 1 },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_unknown_syscall_and_trap_kind() {
+        assert_eq!(
+            verify("sys #0\nsys #1\ntrap #99 %0\nuntrap #3\n"),
+            Err(vec![
+                VerifyError::UnknownSyscall { pc: 3, id: 1 },
+                VerifyError::InvalidTrapKind { pc: 6, kind: 99 },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_jump_targets() {
+        // Jumping to the end of the code stops the VM
+        assert_eq!(verify("load %1 #6\njmp %1\n"), Ok(()));
+        assert_eq!(
+            verify("load %1 #2\njmp %1\n"),
+            Err(vec![VerifyError::JumpIntoInstruction { pc: 4, target: 2 }])
+        );
+        assert_eq!(
+            verify("load %1 #100\nmov %2 %1\njeq %2\ncall %2\n"),
+            Err(vec![
+                VerifyError::JumpOutOfBounds { pc: 7, target: 100 },
+                VerifyError::JumpOutOfBounds { pc: 9, target: 100 },
+            ])
+        );
+        assert_eq!(
+            verify("load %1 #100\njbk %1\n"),
+            Err(vec![VerifyError::JumpOutOfBounds { pc: 4, target: -94 }])
+        );
+        assert_eq!(
+            verify("load %1 #1\ntrap #3 %1\nstop\n"),
+            Err(vec![VerifyError::JumpIntoInstruction { pc: 4, target: 1 }])
+        );
+    }
+
+    #[test]
+    fn test_data_after_code() {
+        // 200 is not an opcode, so everything after the jump is data
+        let input = "load %1 #11\njmp %1\n.byte #200 #255 #1 #0\nstop\n";
+        assert_eq!(verify(input), Ok(()));
+
+        assert_eq!(verify("stop\n.byte #1 #0\n"), Ok(()));
+        assert_eq!(
+            verify("load %1 #7\njmp %1\n.byte #200 #0\n"),
+            Err(vec![VerifyError::JumpIntoData { pc: 4, target: 7 }])
+        );
+
+        // Reachable code must not fall through into the data
+        assert_eq!(
+            verify("load %1 #6\njmp %1\ninc %2\n.byte #200\n"),
+            Err(vec![VerifyError::UnknownOpcode { pc: 8, byte: 200 }])
+        );
+        // `.byte #1 #1` is a LOAD missing its immediate
+        assert_eq!(
+            verify("load %1 #7\njz %1\nstop\ninc %2\n.byte #1 #1\n"),
+            Err(vec![VerifyError::TruncatedInstruction { pc: 9 }])
+        );
+
+        // Without an instruction that ends the code first, bad bytes are still errors
+        assert_eq!(
+            verify("load %1 #0\n.byte #200\nstop\n"),
+            Err(vec![VerifyError::UnknownOpcode { pc: 4, byte: 200 }])
+        );
+    }
+
+    #[test]
+    fn test_unknown_jump_targets() {
+        // Syscalls may change registers, and the last `jmp` is reached with two values of %1
+        assert_eq!(verify("load %1 #2\nsys #0\njmp %1\n"), Ok(()));
+        assert_eq!(verify("load %1 #1\ninc %1\njmp %1\n"), Ok(()));
+        assert_eq!(
+            verify("load %1 #16\nload %2 #14\njz %2\nload %1 #2\njmp %1\n"),
+            Ok(())
+        );
+    }
+}
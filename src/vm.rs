@@ -1,622 +1,3355 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 
+use crate::device::{Device, DeviceError};
+use crate::error::{TrapKind, VmError};
+use crate::gas::GasSchedule;
+use crate::instruction::Instruction;
+use crate::line_table::{LineTable, SourceLocation};
 use crate::opcode::OpCode;
 
+// The reason the VM stopped executing without an error
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HaltReason {
+    // A STOP instruction was executed
+    Stopped,
+    // The program counter reached the end of the code
+    EndOfCode,
+    // A syscall asked the VM to pause by calling `VM::yield_execution`
+    Yielded,
+    // The program counter reached the target passed to `VM::run_until`
+    ReachedTarget,
+    // The next instruction costs more fuel than is left, refuel and run again to resume
+    OutOfFuel,
+    // The program counter reached a breakpoint, the instruction at `pc` has not been executed yet
+    BreakpointHit { pc: usize },
+    // The instruction at `pc` wrote `value` to a watched register
+    WatchpointHit { register: u8, value: i64, pc: usize },
+    // An `InterruptHandle` was triggered or the deadline of `VM::run_with_deadline` passed
+    Interrupted,
+}
+
+// The condition under which a register watchpoint triggers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Watchpoint {
+    // Any write to the register
+    Write,
+    // A write of this specific value to the register
+    WriteValue(i64),
+}
+
+// The state of the VM after executing a single instruction with `VM::step`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExecutionState {
+    // The instruction executed and the VM can keep going
+    Running,
+    // The VM stopped normally, calling `step` again resumes after the halting instruction
+    Halted(HaltReason),
+    // The instruction raised an error, the program counter is left on the faulting instruction
+    Trapped(VmError),
+}
+
+// Used to stop a running VM from another thread
+// The VM checks the handle between instructions and halts with `HaltReason::Interrupted`
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    // Requests the VM to stop before its next instruction
+    // If the VM is not running, the next call to `run` or `step` stops immediately
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    // Clears the request, returning whether there was one
+    fn take(&self) -> bool {
+        self.interrupted.swap(false, Ordering::Relaxed)
+    }
+}
+
+// The closure behind a syscall, returning false if the syscall failed
+// Host syscalls also receive the VM's host context, which is moved out of the VM for the duration of the call
+// Handlers are Send, so a VM can be moved to another thread along with its syscalls
+pub enum SyscallHandler<H> {
+    Vm(Arc<Mutex<VmSyscall<H>>>),
+    Host(Arc<Mutex<HostSyscall<H>>>),
+}
+
+pub type VmSyscall<H> = dyn FnMut(&mut VM<H>) -> bool + Send;
+pub type HostSyscall<H> = dyn FnMut(&mut VM<H>, &mut H) -> bool + Send;
+
+impl<H> Clone for SyscallHandler<H> {
+    fn clone(&self) -> Self {
+        match self {
+            SyscallHandler::Vm(handler) => SyscallHandler::Vm(handler.clone()),
+            SyscallHandler::Host(handler) => SyscallHandler::Host(handler.clone()),
+        }
+    }
+}
+
+// A user-defined syscall and the fuel it consumes on top of the SYS instruction itself
+// The handler is shared between clones of the VM, so state captured by the closure is shared too
+pub struct Syscall<H> {
+    pub handler: SyscallHandler<H>,
+    pub cost: u64,
+}
+
+impl<H> Clone for Syscall<H> {
+    fn clone(&self) -> Self {
+        Syscall {
+            handler: self.handler.clone(),
+            cost: self.cost,
+        }
+    }
+}
+
+// Size of the linear memory of a new VM, in bytes
+pub const DEFAULT_MEMORY_SIZE: usize = 64 * 1024;
+
+// Maximum number of values on the stack and of nested calls of a new VM
+pub const DEFAULT_STACK_LIMIT: usize = 1024;
+
+// Registers written by the VM before jumping to a trap handler
+// The cause is the code of the `TrapKind`, and the pc is the start of the faulting instruction
+pub const TRAP_CAUSE_REGISTER: u8 = 254;
+pub const TRAP_PC_REGISTER: u8 = 255;
+
+// Devices are only locked for the duration of a single access or tick, so a device that panicked
+// while locked is still usable
+fn lock(device: &SharedDevice) -> MutexGuard<'_, dyn Device + Send + 'static> {
+    device.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// The bits of a value kept by a memory access of `width` bytes
+fn width_mask(width: usize) -> i64 {
+    match width {
+        8 => -1,
+        width => (1 << (width * 8)) - 1,
+    }
+}
+
+// A subroutine call made by a CALL instruction, or an interrupt handler, that has not returned yet
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CallFrame {
+    // Address of the CALL instruction, or of the instruction that was interrupted
+    pub call_site: usize,
+    // Address of the called subroutine or interrupt handler
+    pub target: usize,
+    // Address execution continues from once the subroutine returns
+    pub return_address: usize,
+    // The interrupt being handled, or None for a CALL
+    pub interrupt: Option<u8>,
+}
+
+type SharedDevice = Arc<Mutex<dyn Device + Send>>;
+
+// A device attached to the guest's address space
 #[derive(Clone)]
-pub struct VM {
+struct MappedDevice {
+    range: Range<usize>,
+    device: SharedDevice,
+}
+
+// State saved when dispatching an interrupt and restored by RETI
+#[derive(Copy, Clone, Debug)]
+struct InterruptedState {
+    flags: Flags,
+    comparison: bool,
+}
+
+// Set of pending interrupt numbers, one bit each, so an interrupt raised again before it is
+// dispatched is only delivered once
+#[derive(Copy, Clone, Debug, Default)]
+struct PendingInterrupts([u64; 4]);
+
+impl PendingInterrupts {
+    fn insert(&mut self, interrupt: u8) {
+        self.0[interrupt as usize / 64] |= 1 << (interrupt % 64);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|bits| *bits == 0)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX)
+            .filter(|&interrupt| self.0[interrupt as usize / 64] & (1 << (interrupt % 64)) != 0)
+    }
+
+    // Removes and returns the lowest pending interrupt number
+    fn pop_first(&mut self) -> Option<u8> {
+        let interrupt = self.iter().next()?;
+        self.0[interrupt as usize / 64] &= !(1 << (interrupt % 64));
+        Some(interrupt)
+    }
+}
+
+// Status flags set by arithmetic and comparison instructions and read by the conditional branches
+// Comparisons set them as if computing reg1 - reg2
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    // The result was zero
+    pub zero: bool,
+    // The result was negative
+    pub negative: bool,
+    // The operation carried out of (or borrowed into) the highest bit, treating operands as unsigned
+    pub carry: bool,
+    // The operation overflowed, treating operands as signed
+    pub overflow: bool,
+}
+
+impl Flags {
+    fn new(result: i64, carry: bool, overflow: bool) -> Flags {
+        Flags {
+            zero: result == 0,
+            negative: result < 0,
+            carry,
+            overflow,
+        }
+    }
+}
+
+// How arithmetic instructions without an explicit mode handle results that do not fit in an i64
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    // Stop the VM with `VmError::ArithmeticOverflow`
+    #[default]
+    Checked,
+    // Wrap around using two's complement
+    Wrapping,
+    // Clamp the result to i64::MIN or i64::MAX
+    Saturating,
+}
+
+// The arithmetic operations that can overflow
+#[derive(Copy, Clone)]
+enum Operation {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Operation {
+    // The wrapped result along with the carry and overflow flags
+    // Carry is the unsigned carry (or borrow) for addition and subtraction, and matches overflow otherwise
+    fn with_flags(self, a: i64, b: i64) -> (i64, bool, bool) {
+        match self {
+            Operation::Add => {
+                let (result, overflow) = a.overflowing_add(b);
+                (result, (a as u64).overflowing_add(b as u64).1, overflow)
+            }
+            Operation::Sub => {
+                let (result, overflow) = a.overflowing_sub(b);
+                (result, (a as u64) < (b as u64), overflow)
+            }
+            Operation::Mul => {
+                let (result, overflow) = a.overflowing_mul(b);
+                (result, overflow, overflow)
+            }
+            Operation::Div => {
+                let (result, overflow) = a.overflowing_div(b);
+                (result, overflow, overflow)
+            }
+        }
+    }
+
+    fn saturating(self, a: i64, b: i64) -> i64 {
+        match self {
+            Operation::Add => a.saturating_add(b),
+            Operation::Sub => a.saturating_sub(b),
+            Operation::Mul => a.saturating_mul(b),
+            Operation::Div => a.saturating_div(b),
+        }
+    }
+}
+
+// Shifts read the amount as unsigned, so negative amounts behave like amounts of 64 or more
+fn shift_left(value: i64, amount: i64) -> i64 {
+    if (amount as u64) < 64 {
+        value << amount
+    } else {
+        0
+    }
+}
+
+fn shift_right_logical(value: i64, amount: i64) -> i64 {
+    if (amount as u64) < 64 {
+        ((value as u64) >> amount) as i64
+    } else {
+        0
+    }
+}
+
+fn shift_right_arithmetic(value: i64, amount: i64) -> i64 {
+    value >> (amount as u64).min(63)
+}
+
+// `H` is a user-defined host context that is handed to host syscalls alongside the VM
+pub struct VM<H = ()> {
     pub registers: [i64; 256],
+    // Separate bank of floating-point registers, used by the float instructions
+    pub float_registers: [f64; 256],
     pub pc: usize,
     pub code: Vec<u8>,
+    // Source locations of the code, written by the assembler and used in error reports
+    pub line_table: LineTable,
+    // Set by the comparison instructions and read by JEQ and JNE
     pub comparison: bool,
-    pub syscalls: HashMap<u16, fn(&mut VM) -> bool>,
+    pub flags: Flags,
+    // Overflow behaviour of ADD, SUB, MUL and DIV
+    pub arithmetic_mode: ArithmeticMode,
+    // Byte-addressable linear memory, accessed by the load and store instructions
+    pub memory: Vec<u8>,
+    // Values pushed by PUSH and popped by POP
+    pub stack: Vec<i64>,
+    pub stack_limit: usize,
+    // Active subroutine calls, innermost last
+    call_frames: Vec<CallFrame>,
+    pub call_depth_limit: usize,
+    pub syscalls: HashMap<u16, Syscall<H>>,
+    pub gas_schedule: GasSchedule,
+    // None only while a host syscall is running
+    host: Option<H>,
+    // Remaining fuel, or None if execution is not metered
+    fuel: Option<u64>,
+    breakpoints: HashSet<usize>,
+    watchpoints: HashMap<u8, Watchpoint>,
+    // Breakpoint that was just reported, so resuming executes its instruction instead of stopping again
+    resumed_breakpoint: Option<usize>,
+    // Watched register write made by the current instruction
+    watchpoint_hit: Option<(u8, i64)>,
+    // Not shared with clones, which get a handle of their own
+    interrupt: InterruptHandle,
+    deadline: Option<Instant>,
+    // Start of the instruction currently being executed, used for error reporting
+    instruction_pc: usize,
+    // Set by a syscall to pause execution after the current instruction
+    yielded: bool,
+    // Ids of the syscalls whose handlers are currently running on this VM
+    running_syscalls: HashSet<u16>,
+    // Addresses the VM jumps to when a runtime fault happens, installed by the TRAP instruction
+    trap_handlers: HashMap<TrapKind, usize>,
+    // Interrupt vector table, mapping interrupt numbers to handler addresses
+    interrupt_vectors: HashMap<u8, usize>,
+    // Interrupts raised by the host or devices and not dispatched yet
+    pending_interrupts: PendingInterrupts,
+    interrupts_enabled: bool,
+    // One entry per interrupt frame on the call stack
+    interrupted_states: Vec<InterruptedState>,
+    // Shared with clones of the VM, like syscalls
+    devices: Vec<MappedDevice>,
+}
+
+// Implemented by hand so that every clone gets its own `InterruptHandle`
+impl<H: Clone> Clone for VM<H> {
+    fn clone(&self) -> Self {
+        VM {
+            registers: self.registers,
+            float_registers: self.float_registers,
+            pc: self.pc,
+            code: self.code.clone(),
+            line_table: self.line_table.clone(),
+            comparison: self.comparison,
+            flags: self.flags,
+            arithmetic_mode: self.arithmetic_mode,
+            memory: self.memory.clone(),
+            stack: self.stack.clone(),
+            stack_limit: self.stack_limit,
+            call_frames: self.call_frames.clone(),
+            call_depth_limit: self.call_depth_limit,
+            syscalls: self.syscalls.clone(),
+            gas_schedule: self.gas_schedule.clone(),
+            host: self.host.clone(),
+            fuel: self.fuel,
+            breakpoints: self.breakpoints.clone(),
+            watchpoints: self.watchpoints.clone(),
+            resumed_breakpoint: self.resumed_breakpoint,
+            watchpoint_hit: self.watchpoint_hit,
+            interrupt: InterruptHandle::default(),
+            deadline: self.deadline,
+            instruction_pc: self.instruction_pc,
+            yielded: self.yielded,
+            running_syscalls: self.running_syscalls.clone(),
+            trap_handlers: self.trap_handlers.clone(),
+            interrupt_vectors: self.interrupt_vectors.clone(),
+            pending_interrupts: self.pending_interrupts,
+            interrupts_enabled: self.interrupts_enabled,
+            interrupted_states: self.interrupted_states.clone(),
+            devices: self.devices.clone(),
+        }
+    }
+}
+
+impl<H: Default> Default for VM<H> {
+    fn default() -> Self {
+        Self::with_host(H::default())
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
+        VM::with_host(())
+    }
+}
+
+impl<H> VM<H> {
+    pub fn with_host(host: H) -> VM<H> {
         VM {
             registers: [0; 256],
+            float_registers: [0.0; 256],
             pc: 0,
             code: vec![],
+            line_table: LineTable::new(),
             comparison: false,
+            flags: Flags::default(),
+            arithmetic_mode: ArithmeticMode::default(),
+            memory: vec![0; DEFAULT_MEMORY_SIZE],
+            stack: Vec::new(),
+            stack_limit: DEFAULT_STACK_LIMIT,
+            call_frames: Vec::new(),
+            call_depth_limit: DEFAULT_STACK_LIMIT,
             syscalls: HashMap::new(),
+            gas_schedule: GasSchedule::new(),
+            host: Some(host),
+            fuel: None,
+            breakpoints: HashSet::new(),
+            watchpoints: HashMap::new(),
+            resumed_breakpoint: None,
+            watchpoint_hit: None,
+            interrupt: InterruptHandle::default(),
+            deadline: None,
+            instruction_pc: 0,
+            yielded: false,
+            running_syscalls: HashSet::new(),
+            trap_handlers: HashMap::new(),
+            interrupt_vectors: HashMap::new(),
+            pending_interrupts: PendingInterrupts::default(),
+            interrupts_enabled: true,
+            interrupted_states: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+
+    // Panics if called from inside a host syscall, which receives the host context as an argument instead
+    pub fn host(&self) -> &H {
+        self.host
+            .as_ref()
+            .expect("host context is borrowed by a running host syscall")
+    }
+
+    // Panics if called from inside a host syscall, which receives the host context as an argument instead
+    pub fn host_mut(&mut self) -> &mut H {
+        self.host
+            .as_mut()
+            .expect("host context is borrowed by a running host syscall")
+    }
+
+    // Consumes the VM, returning its host context
+    pub fn into_host(self) -> H {
+        self.host
+            .expect("host context is borrowed by a running host syscall")
+    }
+
+    // Runs until a STOP instruction, the end of the code, a yielding syscall or an error
+    // On error the program counter is left on the faulting instruction
+    pub fn run(&mut self) -> Result<HaltReason, VmError> {
+        loop {
+            match self.step() {
+                ExecutionState::Running => {}
+                ExecutionState::Halted(reason) => return Ok(reason),
+                ExecutionState::Trapped(e) => return Err(e),
+            }
         }
     }
 
-    pub fn run(&mut self) {
-        while self.pc < self.code.len() {
-            if !self.execute_instruction() {
-                break;
+    // Like `run`, but also stops as soon as the program counter reaches `pc`
+    // At least one instruction is executed, so calling this in a loop steps through each visit of `pc`
+    pub fn run_until(&mut self, pc: usize) -> Result<HaltReason, VmError> {
+        loop {
+            match self.step() {
+                ExecutionState::Running if self.pc == pc => return Ok(HaltReason::ReachedTarget),
+                ExecutionState::Running => {}
+                ExecutionState::Halted(reason) => return Ok(reason),
+                ExecutionState::Trapped(e) => return Err(e),
             }
         }
     }
 
-    fn execute_instruction(&mut self) -> bool {
-        let opcode = OpCode::from(self.read_u8());
+    // Runs with exactly `fuel` fuel available, replacing any fuel left from a previous run
+    // If the fuel runs out the VM halts with `HaltReason::OutOfFuel` before the instruction that could not be paid for
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<HaltReason, VmError> {
+        self.fuel = Some(fuel);
+        self.run()
+    }
 
-        match opcode {
-            OpCode::STOP => false,
-            OpCode::LOAD => {
-                let register = self.read_u8() as usize;
-                let value = self.read_u16() as i64;
-                self.registers[register] = value;
-                true
+    // Adds fuel to a metered VM, or starts metering with `fuel` if it was unlimited
+    pub fn refuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    // Sets the remaining fuel, None disables metering
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Runs until the program halts or `timeout` has passed, in which case it stops with `HaltReason::Interrupted`
+    // The deadline only applies to this call, running the VM again resumes without one
+    pub fn run_with_deadline(&mut self, timeout: Duration) -> Result<HaltReason, VmError> {
+        self.deadline = Some(Instant::now() + timeout);
+        let result = self.run();
+        self.deadline = None;
+        result
+    }
+
+    // Returns a handle that can interrupt this VM from any thread
+    // Clones of the VM get a handle of their own, so the handle only stops this VM
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    // Executes exactly one instruction
+    pub fn step(&mut self) -> ExecutionState {
+        if self.interrupt.take()
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return ExecutionState::Halted(HaltReason::Interrupted);
+        }
+
+        // Dispatched first, so an interrupt queued at the end of the code still runs its handler
+        if let Err(e) = self.dispatch_interrupt() {
+            return self.raise(e);
+        }
+
+        if self.pc >= self.code.len() {
+            return ExecutionState::Halted(HaltReason::EndOfCode);
+        }
+
+        if self.resumed_breakpoint.take() != Some(self.pc) && self.breakpoints.contains(&self.pc) {
+            self.resumed_breakpoint = Some(self.pc);
+            return ExecutionState::Halted(HaltReason::BreakpointHit { pc: self.pc });
+        }
+
+        if let Some(fuel) = self.fuel {
+            let cost = self.instruction_cost();
+            if cost > fuel {
+                return ExecutionState::Halted(HaltReason::OutOfFuel);
             }
-            OpCode::MOV => {
-                let destination = self.read_u8() as usize;
-                let source = self.read_u8() as usize;
-                self.registers[destination] = self.registers[source];
-                true
+            self.fuel = Some(fuel - cost);
+        }
+
+        self.instruction_pc = self.pc;
+
+        let result = self.execute_instruction();
+        if result.is_ok() {
+            self.tick_devices();
+        }
+        let yielded = std::mem::take(&mut self.yielded);
+        let watchpoint_hit = self.watchpoint_hit.take();
+
+        match (result, watchpoint_hit) {
+            (Ok(_), Some((register, value))) => ExecutionState::Halted(HaltReason::WatchpointHit {
+                register,
+                value,
+                pc: self.instruction_pc,
+            }),
+            (Ok(_), None) if yielded => ExecutionState::Halted(HaltReason::Yielded),
+            (Ok(true), None) => ExecutionState::Running,
+            (Ok(false), None) => ExecutionState::Halted(HaltReason::Stopped),
+            (Err(e), _) => self.raise(e),
+        }
+    }
+
+    // Jumps to the trap handler for the error if one is installed, otherwise stops at the faulting pc
+    fn raise(&mut self, e: VmError) -> ExecutionState {
+        match self.trap_handlers.get(&e.trap_kind()) {
+            // Written directly, so trap registers do not trigger watchpoints
+            Some(&handler) => {
+                self.registers[TRAP_CAUSE_REGISTER as usize] = e.trap_kind().code() as i64;
+                self.registers[TRAP_PC_REGISTER as usize] = e.pc() as i64;
+                self.pc = handler;
+                ExecutionState::Running
             }
-            OpCode::ADD => {
-                let destination = self.read_u8() as usize;
-                let source1 = self.read_u8() as usize;
-                let source2 = self.read_u8() as usize;
-                self.registers[destination] = self.registers[source1] + self.registers[source2];
-                true
+            None => {
+                self.pc = e.pc();
+                ExecutionState::Trapped(e)
             }
-            OpCode::SUB => {
-                let destination = self.read_u8() as usize;
-                let source1 = self.read_u8() as usize;
-                let source2 = self.read_u8() as usize;
-                self.registers[destination] = self.registers[source1] - self.registers[source2];
-                true
+        }
+    }
+
+    // Stops execution with `HaltReason::BreakpointHit` before the instruction at `pc` is executed
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    // Returns true if there was a breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // Stops execution with `HaltReason::WatchpointHit` after an instruction writes to `register`
+    // Only writes made by guest instructions are watched, the host writing to `registers` directly is not
+    pub fn watch_register(&mut self, register: u8, watchpoint: Watchpoint) {
+        self.watchpoints.insert(register, watchpoint);
+    }
+
+    // Returns true if the register was being watched
+    pub fn unwatch_register(&mut self, register: u8) -> bool {
+        self.watchpoints.remove(&register).is_some()
+    }
+
+    // Faults of this kind jump to `address` instead of stopping the VM, like the TRAP instruction
+    pub fn set_trap_handler(&mut self, kind: TrapKind, address: usize) {
+        self.trap_handlers.insert(kind, address);
+    }
+
+    // Returns true if a handler was installed for this kind
+    pub fn remove_trap_handler(&mut self, kind: TrapKind) -> bool {
+        self.trap_handlers.remove(&kind).is_some()
+    }
+
+    pub fn trap_handler(&self, kind: TrapKind) -> Option<usize> {
+        self.trap_handlers.get(&kind).copied()
+    }
+
+    // Queues an interrupt, which is dispatched before the next instruction once interrupts are enabled
+    // Interrupts are dispatched one at a time, lowest number first, and queueing an interrupt that is
+    // already pending has no effect
+    pub fn queue_interrupt(&mut self, interrupt: u8) {
+        self.pending_interrupts.insert(interrupt);
+    }
+
+    // In ascending order
+    pub fn pending_interrupts(&self) -> impl Iterator<Item = u8> + '_ {
+        self.pending_interrupts.iter()
+    }
+
+    pub fn has_pending_interrupts(&self) -> bool {
+        !self.pending_interrupts.is_empty()
+    }
+
+    // Interrupts without a vector are discarded when they are dispatched
+    // The address is checked on dispatch, since code can still be written after setting the vector
+    pub fn set_interrupt_vector(&mut self, interrupt: u8, address: usize) {
+        self.interrupt_vectors.insert(interrupt, address);
+    }
+
+    // Returns true if the interrupt had a vector
+    pub fn remove_interrupt_vector(&mut self, interrupt: u8) -> bool {
+        self.interrupt_vectors.remove(&interrupt).is_some()
+    }
+
+    pub fn interrupt_vector(&self, interrupt: u8) -> Option<usize> {
+        self.interrupt_vectors.get(&interrupt).copied()
+    }
+
+    // Enabled by default, and changed by the EI and DI instructions
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        self.interrupts_enabled = enabled;
+    }
+
+    // Maps `device` at `address`, returning a handle the host can use to inspect it
+    // Devices take precedence over memory, so they are usually mapped past the end of it
+    pub fn attach_device<D: Device + Send + 'static>(
+        &mut self,
+        address: usize,
+        device: D,
+    ) -> Result<Arc<Mutex<D>>, DeviceError> {
+        let size = device.size();
+        let end = address
+            .checked_add(size)
+            .ok_or(DeviceError::AddressOverflow { address, size })?;
+        let range = address..end;
+        if self
+            .devices
+            .iter()
+            .any(|mapped| mapped.range.start < range.end && range.start < mapped.range.end)
+        {
+            return Err(DeviceError::Overlap { range });
+        }
+
+        let device = Arc::new(Mutex::new(device));
+        self.devices.push(MappedDevice {
+            range,
+            device: device.clone(),
+        });
+        Ok(device)
+    }
+
+    fn tick_devices(&mut self) {
+        for mapped in &self.devices {
+            if let Some(interrupt) = lock(&mapped.device).tick() {
+                self.pending_interrupts.insert(interrupt);
             }
-            OpCode::MUL => {
-                let destination = self.read_u8() as usize;
-                let source1 = self.read_u8() as usize;
-                let source2 = self.read_u8() as usize;
-                self.registers[destination] = self.registers[source1] * self.registers[source2];
-                true
+        }
+    }
+
+    // The device a guest memory access falls in, if any
+    // Accesses that overlap a device without fitting entirely in it are out of bounds
+    fn device_access(
+        &self,
+        address: i64,
+        width: usize,
+    ) -> Result<Option<(usize, SharedDevice)>, VmError> {
+        let Ok(start) = usize::try_from(address) else {
+            return Ok(None);
+        };
+        let end = start.saturating_add(width);
+
+        match self
+            .devices
+            .iter()
+            .find(|mapped| mapped.range.start < end && start < mapped.range.end)
+        {
+            Some(mapped) if mapped.range.start <= start && end <= mapped.range.end => {
+                Ok(Some((start - mapped.range.start, mapped.device.clone())))
             }
-            OpCode::DIV => {
-                let destination = self.read_u8() as usize;
-                let source1 = self.read_u8() as usize;
-                let source2 = self.read_u8() as usize;
-                self.registers[destination] = self.registers[source1] / self.registers[source2];
-                true
+            Some(_) => Err(VmError::MemoryOutOfBounds {
+                pc: self.instruction_pc,
+                address,
+                width,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    // Jumps to the handler of the lowest pending interrupt, pushing an interrupt frame on the call stack
+    // Interrupts are disabled while the handler runs, until RETI returns from it
+    // A vector outside of the code raises `JumpOutOfBounds` at the interrupted pc
+    fn dispatch_interrupt(&mut self) -> Result<(), VmError> {
+        if !self.interrupts_enabled || self.call_frames.len() >= self.call_depth_limit {
+            return Ok(());
+        }
+
+        while let Some(interrupt) = self.pending_interrupts.pop_first() {
+            let Some(&handler) = self.interrupt_vectors.get(&interrupt) else {
+                continue;
+            };
+            if handler >= self.code.len() {
+                return Err(VmError::JumpOutOfBounds {
+                    pc: self.pc,
+                    target: handler as i64,
+                });
             }
-            OpCode::JMP => {
-                let address = self.registers[self.read_u8() as usize] as usize;
-                self.pc = address;
-                true
+
+            self.call_frames.push(CallFrame {
+                call_site: self.pc,
+                target: handler,
+                return_address: self.pc,
+                interrupt: Some(interrupt),
+            });
+            self.interrupted_states.push(InterruptedState {
+                flags: self.flags,
+                comparison: self.comparison,
+            });
+            self.interrupts_enabled = false;
+            self.pc = handler;
+            return Ok(());
+        }
+
+        Ok(())
+    }
+
+    // Called from a syscall to pause execution once the SYS instruction completes
+    // The VM can be resumed by calling `run` or `step` again
+    pub fn yield_execution(&mut self) {
+        self.yielded = true;
+    }
+
+    // The fuel needed to execute the instruction at pc
+    // Malformed instructions are charged for their opcode only, executing them will raise the error
+    fn instruction_cost(&self) -> u64 {
+        let opcode = OpCode::from(self.code[self.pc]);
+        let mut cost = self.gas_schedule.cost(opcode);
+
+        if let Ok((Instruction::Sys { id }, _)) = Instruction::decode(&self.code, self.pc) {
+            if let Some(syscall) = self.syscalls.get(&id) {
+                cost = cost.saturating_add(syscall.cost);
             }
-            OpCode::JFW => {
-                let offset = self.registers[self.read_u8() as usize] as usize;
-                self.pc += offset;
-                true
+        }
+
+        cost
+    }
+
+    // Executes a single instruction, returning false if the VM should stop
+    fn execute_instruction(&mut self) -> Result<bool, VmError> {
+        let (instruction, len) = Instruction::decode(&self.code, self.pc)?;
+        self.pc += len;
+
+        match instruction {
+            Instruction::Stop {} => Ok(false),
+            Instruction::Load { dst, imm } => {
+                self.set_register(dst, imm as i64);
+                Ok(true)
             }
-            OpCode::JBK => {
-                let offset = self.registers[self.read_u8() as usize] as usize;
-                self.pc -= offset;
-                true
+            Instruction::Mov { dst, src } => {
+                self.set_register(dst, self.registers[src as usize]);
+                Ok(true)
             }
-            OpCode::EQ => {
-                let register1 = self.read_u8() as usize;
-                let register2 = self.read_u8() as usize;
-                self.comparison = self.registers[register1] == self.registers[register2];
-                true
+            Instruction::Add { dst, a, b } => {
+                self.arithmetic(Operation::Add, self.arithmetic_mode, dst, a, b)
             }
-            OpCode::NEQ => {
-                let register1 = self.read_u8() as usize;
-                let register2 = self.read_u8() as usize;
-                self.comparison = self.registers[register1] != self.registers[register2];
-                true
+            Instruction::Sub { dst, a, b } => {
+                self.arithmetic(Operation::Sub, self.arithmetic_mode, dst, a, b)
             }
-            OpCode::GT => {
-                let register1 = self.read_u8() as usize;
-                let register2 = self.read_u8() as usize;
-                self.comparison = self.registers[register1] > self.registers[register2];
-                true
+            Instruction::Mul { dst, a, b } => {
+                self.arithmetic(Operation::Mul, self.arithmetic_mode, dst, a, b)
             }
-            OpCode::LT => {
-                let register1 = self.read_u8() as usize;
-                let register2 = self.read_u8() as usize;
-                self.comparison = self.registers[register1] < self.registers[register2];
-                true
+            Instruction::Div { dst, a, b } => {
+                self.arithmetic(Operation::Div, self.arithmetic_mode, dst, a, b)
             }
-            OpCode::GTE => {
-                let register1 = self.read_u8() as usize;
-                let register2 = self.read_u8() as usize;
-                self.comparison = self.registers[register1] >= self.registers[register2];
-                true
+            Instruction::Jmp { addr } => {
+                self.jump(self.registers[addr as usize])?;
+                Ok(true)
             }
-            OpCode::LTE => {
-                let register1 = self.read_u8() as usize;
-                let register2 = self.read_u8() as usize;
-                self.comparison = self.registers[register1] <= self.registers[register2];
-                true
+            Instruction::Jfw { offset } => {
+                let offset = self.registers[offset as usize];
+                self.jump((self.pc as i64).saturating_add(offset))?;
+                Ok(true)
             }
-            OpCode::JEQ => {
-                let address = self.registers[self.read_u8() as usize] as usize;
+            Instruction::Jbk { offset } => {
+                let offset = self.registers[offset as usize];
+                self.jump((self.pc as i64).saturating_sub(offset))?;
+                Ok(true)
+            }
+            Instruction::Eq { a, b } => self.compare(a, b, |a, b| a == b),
+            Instruction::Neq { a, b } => self.compare(a, b, |a, b| a != b),
+            Instruction::Gt { a, b } => self.compare(a, b, |a, b| a > b),
+            Instruction::Lt { a, b } => self.compare(a, b, |a, b| a < b),
+            Instruction::Gte { a, b } => self.compare(a, b, |a, b| a >= b),
+            Instruction::Lte { a, b } => self.compare(a, b, |a, b| a <= b),
+            Instruction::Jeq { addr } => {
                 if self.comparison {
-                    self.pc = address;
+                    self.jump(self.registers[addr as usize])?;
                 }
-                true
+                Ok(true)
             }
-            OpCode::JNE => {
-                let address = self.registers[self.read_u8() as usize] as usize;
+            Instruction::Jne { addr } => {
                 if !self.comparison {
-                    self.pc = address;
+                    self.jump(self.registers[addr as usize])?;
                 }
-                true
+                Ok(true)
             }
-            OpCode::SYS => {
-                let syscall_id = self.read_u16();
-                let syscall = self.syscalls.get(&syscall_id);
+            Instruction::Sys { id } => {
+                let pc = self.instruction_pc;
+                let handler = self
+                    .syscalls
+                    .get(&id)
+                    .ok_or(VmError::UnknownSyscall { id, pc })?
+                    .handler
+                    .clone();
 
-                return match syscall {
-                    Some(syscall) => syscall(self),
-                    None => {
-                        println!("Unknown syscall: {}", syscall_id);
-                        false
+                // A syscall that re-enters itself through a nested `run` on this VM would deadlock on its
+                // own lock, so it fails instead. Clones running the syscall on other threads wait their turn
+                if !self.running_syscalls.insert(id) {
+                    return Err(VmError::SyscallFailed { id, pc });
+                }
+                let success = match handler {
+                    SyscallHandler::Vm(handler) => {
+                        let mut syscall = handler.lock().unwrap_or_else(PoisonError::into_inner);
+                        syscall(self)
                     }
+                    SyscallHandler::Host(handler) => match self.host.take() {
+                        Some(mut host) => {
+                            let mut syscall =
+                                handler.lock().unwrap_or_else(PoisonError::into_inner);
+                            let success = syscall(self, &mut host);
+                            self.host = Some(host);
+                            success
+                        }
+                        None => false,
+                    },
                 };
+                self.running_syscalls.remove(&id);
+
+                if success {
+                    Ok(true)
+                } else {
+                    Err(VmError::SyscallFailed { id, pc })
+                }
             }
-            OpCode::UKWN => {
-                println!("Unknown opcode: {}", self.code[self.pc - 1]);
-                false
+            Instruction::Ld8 { dst, addr } => self.load_memory(1, dst, addr),
+            Instruction::Ld16 { dst, addr } => self.load_memory(2, dst, addr),
+            Instruction::Ld32 { dst, addr } => self.load_memory(4, dst, addr),
+            Instruction::Ld64 { dst, addr } => self.load_memory(8, dst, addr),
+            Instruction::St8 { addr, src } => self.store_memory(1, addr, src),
+            Instruction::St16 { addr, src } => self.store_memory(2, addr, src),
+            Instruction::St32 { addr, src } => self.store_memory(4, addr, src),
+            Instruction::St64 { addr, src } => self.store_memory(8, addr, src),
+            Instruction::Call { addr } => {
+                if self.call_frames.len() >= self.call_depth_limit {
+                    return Err(VmError::StackOverflow {
+                        pc: self.instruction_pc,
+                    });
+                }
+
+                let return_address = self.pc;
+                self.jump(self.registers[addr as usize])?;
+                self.call_frames.push(CallFrame {
+                    call_site: self.instruction_pc,
+                    target: self.pc,
+                    return_address,
+                    interrupt: None,
+                });
+                Ok(true)
             }
-        }
-    }
+            Instruction::Ret {} => {
+                let frame = self.call_frames.last().ok_or(VmError::StackUnderflow {
+                    pc: self.instruction_pc,
+                })?;
+                if frame.interrupt.is_some() {
+                    return Err(VmError::InvalidReturn {
+                        pc: self.instruction_pc,
+                    });
+                }
+
+                self.pc = frame.return_address;
+                self.call_frames.pop();
+                Ok(true)
+            }
+            Instruction::Push { src } => {
+                if self.stack.len() >= self.stack_limit {
+                    return Err(VmError::StackOverflow {
+                        pc: self.instruction_pc,
+                    });
+                }
+
+                self.stack.push(self.registers[src as usize]);
+                Ok(true)
+            }
+            Instruction::Pop { dst } => {
+                let value = self.stack.pop().ok_or(VmError::StackUnderflow {
+                    pc: self.instruction_pc,
+                })?;
+                self.set_register(dst, value);
+                Ok(true)
+            }
+            Instruction::Jz { addr } => self.branch(addr, |flags| flags.zero),
+            Instruction::Jnz { addr } => self.branch(addr, |flags| !flags.zero),
+            Instruction::Jlt { addr } => {
+                self.branch(addr, |flags| flags.negative != flags.overflow)
+            }
+            Instruction::Jge { addr } => {
+                self.branch(addr, |flags| flags.negative == flags.overflow)
+            }
+            Instruction::Jgt { addr } => self.branch(addr, |flags| {
+                !flags.zero && flags.negative == flags.overflow
+            }),
+            Instruction::Jle { addr } => {
+                self.branch(addr, |flags| flags.zero || flags.negative != flags.overflow)
+            }
+            Instruction::Jc { addr } => self.branch(addr, |flags| flags.carry),
+            Instruction::Jnc { addr } => self.branch(addr, |flags| !flags.carry),
+            Instruction::Jo { addr } => self.branch(addr, |flags| flags.overflow),
+            Instruction::Jno { addr } => self.branch(addr, |flags| !flags.overflow),
+            Instruction::Addt { dst, a, b } => {
+                self.arithmetic(Operation::Add, ArithmeticMode::Checked, dst, a, b)
+            }
+            Instruction::Subt { dst, a, b } => {
+                self.arithmetic(Operation::Sub, ArithmeticMode::Checked, dst, a, b)
+            }
+            Instruction::Mult { dst, a, b } => {
+                self.arithmetic(Operation::Mul, ArithmeticMode::Checked, dst, a, b)
+            }
+            Instruction::Addw { dst, a, b } => {
+                self.arithmetic(Operation::Add, ArithmeticMode::Wrapping, dst, a, b)
+            }
+            Instruction::Subw { dst, a, b } => {
+                self.arithmetic(Operation::Sub, ArithmeticMode::Wrapping, dst, a, b)
+            }
+            Instruction::Mulw { dst, a, b } => {
+                self.arithmetic(Operation::Mul, ArithmeticMode::Wrapping, dst, a, b)
+            }
+            Instruction::Adds { dst, a, b } => {
+                self.arithmetic(Operation::Add, ArithmeticMode::Saturating, dst, a, b)
+            }
+            Instruction::Subs { dst, a, b } => {
+                self.arithmetic(Operation::Sub, ArithmeticMode::Saturating, dst, a, b)
+            }
+            Instruction::Muls { dst, a, b } => {
+                self.arithmetic(Operation::Mul, ArithmeticMode::Saturating, dst, a, b)
+            }
+            Instruction::And { dst, a, b } => self.non_overflowing_binary(dst, a, b, |a, b| a & b),
+            Instruction::Or { dst, a, b } => self.non_overflowing_binary(dst, a, b, |a, b| a | b),
+            Instruction::Xor { dst, a, b } => self.non_overflowing_binary(dst, a, b, |a, b| a ^ b),
+            Instruction::Not { dst, src } => {
+                let value = !self.registers[src as usize];
+                self.flags = Flags::new(value, false, false);
+                self.set_register(dst, value);
+                Ok(true)
+            }
+            Instruction::Shl { dst, a, b } => self.non_overflowing_binary(dst, a, b, shift_left),
+            Instruction::Shr { dst, a, b } => {
+                self.non_overflowing_binary(dst, a, b, shift_right_logical)
+            }
+            Instruction::Sar { dst, a, b } => {
+                self.non_overflowing_binary(dst, a, b, shift_right_arithmetic)
+            }
+            Instruction::Mod { dst, a, b } => {
+                if self.registers[b as usize] == 0 {
+                    return Err(VmError::DivisionByZero {
+                        pc: self.instruction_pc,
+                    });
+                }
+
+                // i64::MIN % -1 is 0, it only overflows in Rust because the matching division does
+                let value = self.registers[a as usize].wrapping_rem(self.registers[b as usize]);
+                self.flags = Flags::new(value, false, false);
+                self.set_register(dst, value);
+                Ok(true)
+            }
+            Instruction::Neg { dst, src } => {
+                let value = self.registers[src as usize];
+                let value = self.calculate(Operation::Sub, 0, value, self.arithmetic_mode)?;
+                self.set_register(dst, value);
+                Ok(true)
+            }
+            Instruction::Abs { dst, src } => {
+                let value = self.registers[src as usize];
+                let value = if value < 0 {
+                    self.calculate(Operation::Sub, 0, value, self.arithmetic_mode)?
+                } else {
+                    self.flags = Flags::new(value, false, false);
+                    value
+                };
+                self.set_register(dst, value);
+                Ok(true)
+            }
+            Instruction::Min { dst, a, b } => self.non_overflowing_binary(dst, a, b, i64::min),
+            Instruction::Max { dst, a, b } => self.non_overflowing_binary(dst, a, b, i64::max),
+            Instruction::Inc { reg } => {
+                let value = self.calculate(
+                    Operation::Add,
+                    self.registers[reg as usize],
+                    1,
+                    self.arithmetic_mode,
+                )?;
+                self.set_register(reg, value);
+                Ok(true)
+            }
+            Instruction::Dec { reg } => {
+                let value = self.calculate(
+                    Operation::Sub,
+                    self.registers[reg as usize],
+                    1,
+                    self.arithmetic_mode,
+                )?;
+                self.set_register(reg, value);
+                Ok(true)
+            }
+            Instruction::Addi { dst, src, imm } => {
+                self.arithmetic_immediate(Operation::Add, dst, src, imm)
+            }
+            Instruction::Subi { dst, src, imm } => {
+                self.arithmetic_immediate(Operation::Sub, dst, src, imm)
+            }
+            Instruction::Muli { dst, src, imm } => {
+                self.arithmetic_immediate(Operation::Mul, dst, src, imm)
+            }
+            Instruction::Eqi { reg, imm } => self.compare_immediate(reg, imm, |a, b| a == b),
+            Instruction::Neqi { reg, imm } => self.compare_immediate(reg, imm, |a, b| a != b),
+            Instruction::Gti { reg, imm } => self.compare_immediate(reg, imm, |a, b| a > b),
+            Instruction::Lti { reg, imm } => self.compare_immediate(reg, imm, |a, b| a < b),
+            Instruction::Gtei { reg, imm } => self.compare_immediate(reg, imm, |a, b| a >= b),
+            Instruction::Ltei { reg, imm } => self.compare_immediate(reg, imm, |a, b| a <= b),
+            Instruction::Loadw { dst, imm } => {
+                self.set_register(dst, imm);
+                Ok(true)
+            }
+            Instruction::Fload { dst, imm } => {
+                self.float_registers[dst as usize] = imm;
+                Ok(true)
+            }
+            Instruction::Fmov { dst, src } => {
+                self.float_registers[dst as usize] = self.float_registers[src as usize];
+                Ok(true)
+            }
+            Instruction::Fadd { dst, a, b } => self.float_arithmetic(dst, a, b, |a, b| a + b),
+            Instruction::Fsub { dst, a, b } => self.float_arithmetic(dst, a, b, |a, b| a - b),
+            Instruction::Fmul { dst, a, b } => self.float_arithmetic(dst, a, b, |a, b| a * b),
+            Instruction::Fdiv { dst, a, b } => self.float_arithmetic(dst, a, b, |a, b| a / b),
+            Instruction::Feq { a, b } => self.float_compare(a, b, |a, b| a == b),
+            Instruction::Fneq { a, b } => self.float_compare(a, b, |a, b| a != b),
+            Instruction::Fgt { a, b } => self.float_compare(a, b, |a, b| a > b),
+            Instruction::Flt { a, b } => self.float_compare(a, b, |a, b| a < b),
+            Instruction::Fgte { a, b } => self.float_compare(a, b, |a, b| a >= b),
+            Instruction::Flte { a, b } => self.float_compare(a, b, |a, b| a <= b),
+            Instruction::Itof { dst, src } => {
+                self.float_registers[dst as usize] = self.registers[src as usize] as f64;
+                Ok(true)
+            }
+            Instruction::Ftoi { dst, src } => {
+                // `as` saturates out of range values and converts NaN to 0
+                self.set_register(dst, self.float_registers[src as usize] as i64);
+                Ok(true)
+            }
+            Instruction::Trap { kind, addr } => {
+                let kind = self.trap_kind(kind)?;
+                let address = self.registers[addr as usize];
+                if address < 0 || address as u64 > self.code.len() as u64 {
+                    return Err(VmError::JumpOutOfBounds {
+                        pc: self.instruction_pc,
+                        target: address,
+                    });
+                }
+                self.trap_handlers.insert(kind, address as usize);
+                Ok(true)
+            }
+            Instruction::Untrap { kind } => {
+                let kind = self.trap_kind(kind)?;
+                self.trap_handlers.remove(&kind);
+                Ok(true)
+            }
+            Instruction::Ei {} => {
+                self.interrupts_enabled = true;
+                Ok(true)
+            }
+            Instruction::Di {} => {
+                self.interrupts_enabled = false;
+                Ok(true)
+            }
+            Instruction::Reti {} => {
+                let frame = self.call_frames.last().ok_or(VmError::StackUnderflow {
+                    pc: self.instruction_pc,
+                })?;
+                if frame.interrupt.is_none() {
+                    return Err(VmError::InvalidReturn {
+                        pc: self.instruction_pc,
+                    });
+                }
+
+                self.pc = frame.return_address;
+                self.call_frames.pop();
+                let state = self
+                    .interrupted_states
+                    .pop()
+                    .expect("every interrupt frame has a saved state");
+                self.flags = state.flags;
+                self.comparison = state.comparison;
+                self.interrupts_enabled = true;
+                Ok(true)
+            }
+        }
+    }
+
+    // Shared implementation of the three-register arithmetic instructions
+    fn arithmetic(
+        &mut self,
+        operation: Operation,
+        mode: ArithmeticMode,
+        destination: u8,
+        source1: u8,
+        source2: u8,
+    ) -> Result<bool, VmError> {
+        let value = self.calculate(
+            operation,
+            self.registers[source1 as usize],
+            self.registers[source2 as usize],
+            mode,
+        )?;
+        self.set_register(destination, value);
+        Ok(true)
+    }
+
+    // Applies an arithmetic operation in the given overflow mode and updates the flags
+    fn calculate(
+        &mut self,
+        operation: Operation,
+        a: i64,
+        b: i64,
+        mode: ArithmeticMode,
+    ) -> Result<i64, VmError> {
+        if let Operation::Div = operation {
+            if b == 0 {
+                return Err(VmError::DivisionByZero {
+                    pc: self.instruction_pc,
+                });
+            }
+        }
+
+        let (wrapped, carry, overflow) = operation.with_flags(a, b);
+        let value = match mode {
+            _ if !overflow => wrapped,
+            ArithmeticMode::Checked => {
+                return Err(VmError::ArithmeticOverflow {
+                    pc: self.instruction_pc,
+                })
+            }
+            ArithmeticMode::Wrapping => wrapped,
+            ArithmeticMode::Saturating => operation.saturating(a, b),
+        };
+
+        self.flags = Flags::new(value, carry, overflow);
+        Ok(value)
+    }
+
+    // Shared implementation of the three-register instructions that cannot overflow
+    // (bitwise, shifts, min and max), so the carry and overflow flags are cleared
+    fn non_overflowing_binary(
+        &mut self,
+        destination: u8,
+        source1: u8,
+        source2: u8,
+        operation: fn(i64, i64) -> i64,
+    ) -> Result<bool, VmError> {
+        let value = operation(
+            self.registers[source1 as usize],
+            self.registers[source2 as usize],
+        );
+        self.flags = Flags::new(value, false, false);
+        self.set_register(destination, value);
+        Ok(true)
+    }
+
+    // Shared implementation of the comparison instructions
+    fn compare(
+        &mut self,
+        register1: u8,
+        register2: u8,
+        comparison: fn(i64, i64) -> bool,
+    ) -> Result<bool, VmError> {
+        self.set_comparison(
+            self.registers[register1 as usize],
+            self.registers[register2 as usize],
+            comparison,
+        );
+        Ok(true)
+    }
+
+    // Shared implementation of the comparison instructions taking an immediate value
+    fn compare_immediate(
+        &mut self,
+        register: u8,
+        value: i16,
+        comparison: fn(i64, i64) -> bool,
+    ) -> Result<bool, VmError> {
+        self.set_comparison(self.registers[register as usize], value as i64, comparison);
+        Ok(true)
+    }
+
+    // Sets the comparison flag, and the flags as if computing a - b
+    fn set_comparison(&mut self, a: i64, b: i64, comparison: fn(i64, i64) -> bool) {
+        let (result, carry, overflow) = Operation::Sub.with_flags(a, b);
+        self.comparison = comparison(a, b);
+        self.flags = Flags::new(result, carry, overflow);
+    }
+
+    // Shared implementation of the arithmetic instructions taking an immediate value
+    fn arithmetic_immediate(
+        &mut self,
+        operation: Operation,
+        destination: u8,
+        source: u8,
+        value: i16,
+    ) -> Result<bool, VmError> {
+        let value = self.calculate(
+            operation,
+            self.registers[source as usize],
+            value as i64,
+            self.arithmetic_mode,
+        )?;
+        self.set_register(destination, value);
+        Ok(true)
+    }
+
+    // Shared implementation of the float arithmetic instructions, which never fail and leave the flags untouched
+    fn float_arithmetic(
+        &mut self,
+        destination: u8,
+        source1: u8,
+        source2: u8,
+        operation: fn(f64, f64) -> f64,
+    ) -> Result<bool, VmError> {
+        self.float_registers[destination as usize] = operation(
+            self.float_registers[source1 as usize],
+            self.float_registers[source2 as usize],
+        );
+        Ok(true)
+    }
+
+    // Shared implementation of the float comparison instructions, which only set the comparison flag
+    fn float_compare(
+        &mut self,
+        register1: u8,
+        register2: u8,
+        comparison: fn(f64, f64) -> bool,
+    ) -> Result<bool, VmError> {
+        self.comparison = comparison(
+            self.float_registers[register1 as usize],
+            self.float_registers[register2 as usize],
+        );
+        Ok(true)
+    }
+
+    // Validates the trap kind operand of TRAP and UNTRAP
+    fn trap_kind(&self, code: u16) -> Result<TrapKind, VmError> {
+        TrapKind::from_code(code).ok_or(VmError::InvalidTrapKind {
+            pc: self.instruction_pc,
+            kind: code,
+        })
+    }
+
+    // Shared implementation of the flag-based conditional branches
+    fn branch(&mut self, address: u8, condition: fn(&Flags) -> bool) -> Result<bool, VmError> {
+        if condition(&self.flags) {
+            self.jump(self.registers[address as usize])?;
+        }
+        Ok(true)
+    }
+
+    // Shared implementation of the load instructions, reading `width` bytes
+    fn load_memory(&mut self, width: usize, destination: u8, address: u8) -> Result<bool, VmError> {
+        let address = self.registers[address as usize];
+        if let Some((offset, device)) = self.device_access(address, width)? {
+            let value = lock(&device).read(offset, width);
+            self.set_register(destination, value & width_mask(width));
+            return Ok(true);
+        }
+
+        let range = self.memory_range(address, width)?;
+
+        let value = self.memory[range]
+            .iter()
+            .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+        self.set_register(destination, value as i64);
+        Ok(true)
+    }
+
+    // Shared implementation of the store instructions, writing the low `width` bytes of a register
+    fn store_memory(&mut self, width: usize, address: u8, source: u8) -> Result<bool, VmError> {
+        let address = self.registers[address as usize];
+        let value = self.registers[source as usize];
+        if let Some((offset, device)) = self.device_access(address, width)? {
+            lock(&device).write(offset, width, value & width_mask(width));
+            return Ok(true);
+        }
+
+        let range = self.memory_range(address, width)?;
+
+        self.memory[range].copy_from_slice(&value.to_be_bytes()[8 - width..]);
+        Ok(true)
+    }
+
+    // Bounds checks a guest memory access
+    fn memory_range(&self, address: i64, width: usize) -> Result<Range<usize>, VmError> {
+        usize::try_from(address)
+            .ok()
+            .and_then(|start| Some(start..start.checked_add(width)?))
+            .filter(|range| range.end <= self.memory.len())
+            .ok_or(VmError::MemoryOutOfBounds {
+                pc: self.instruction_pc,
+                address,
+                width,
+            })
+    }
+
+    // The subroutine calls that have not returned yet, outermost first
+    pub fn call_frames(&self) -> &[CallFrame] {
+        &self.call_frames
+    }
+
+    // The current program counter followed by the call site of every active call, innermost first
+    pub fn backtrace(&self) -> Vec<usize> {
+        std::iter::once(self.pc)
+            .chain(self.call_frames.iter().rev().map(|frame| frame.call_site))
+            .collect()
+    }
+
+    // The assembly source location of the instruction at `pc`, if the code has a line table
+    pub fn source_location(&self, pc: usize) -> Option<SourceLocation<'_>> {
+        self.line_table.lookup(pc)
+    }
+
+    // Like the error's Display, but pointing at the source location when it is known
+    pub fn describe_error(&self, error: &VmError) -> String {
+        match self.source_location(error.pc()) {
+            Some(location) => format!("{} at {}", error.message(), location),
+            None => error.to_string(),
+        }
+    }
+
+    // Reads `len` bytes of guest memory, returning None if the range is out of bounds
+    pub fn read_memory(&self, address: usize, len: usize) -> Option<&[u8]> {
+        self.memory.get(address..address.checked_add(len)?)
+    }
+
+    // Writes bytes into guest memory, returning false without writing anything if the range is out of bounds
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool {
+        let Some(end) = address.checked_add(bytes.len()) else {
+            return false;
+        };
+
+        match self.memory.get_mut(address..end) {
+            Some(memory) => {
+                memory.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // All register writes made by instructions go through here so watchpoints can see them
+    fn set_register(&mut self, register: u8, value: i64) {
+        self.registers[register as usize] = value;
+
+        match self.watchpoints.get(&register) {
+            Some(Watchpoint::Write) => self.watchpoint_hit = Some((register, value)),
+            Some(Watchpoint::WriteValue(expected)) if *expected == value => {
+                self.watchpoint_hit = Some((register, value))
+            }
+            _ => {}
+        }
+    }
+
+    // Moves the program counter, jumping to the end of the code is allowed and stops the VM
+    fn jump(&mut self, target: i64) -> Result<(), VmError> {
+        if target < 0 || target as u64 > self.code.len() as u64 {
+            return Err(VmError::JumpOutOfBounds {
+                pc: self.instruction_pc,
+                target,
+            });
+        }
+
+        self.pc = target as usize;
+        Ok(())
+    }
+
+    pub fn write_opcode(&mut self, opcode: OpCode) {
+        self.code.push(opcode as u8);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.code.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.code.push((value >> 8) as u8);
+        self.code.push(value as u8);
+    }
+
+    pub fn write_i64(&mut self, value: i64) {
+        self.code.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.code.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_instruction(&mut self, instruction: Instruction) {
+        instruction.encode(&mut self.code);
+    }
+
+    // Accepts plain functions as well as closures capturing host state
+    pub fn register_syscall(
+        &mut self,
+        id: u16,
+        syscall: impl FnMut(&mut VM<H>) -> bool + Send + 'static,
+    ) {
+        self.register_syscall_with_cost(id, 0, syscall);
+    }
+
+    // Registers a syscall that consumes `cost` fuel in addition to the cost of the SYS instruction
+    pub fn register_syscall_with_cost(
+        &mut self,
+        id: u16,
+        cost: u64,
+        syscall: impl FnMut(&mut VM<H>) -> bool + Send + 'static,
+    ) {
+        self.insert_syscall(id, cost, SyscallHandler::Vm(Arc::new(Mutex::new(syscall))));
+    }
+
+    // Registers a syscall that receives mutable access to both the VM and its host context
+    pub fn register_host_syscall(
+        &mut self,
+        id: u16,
+        syscall: impl FnMut(&mut VM<H>, &mut H) -> bool + Send + 'static,
+    ) {
+        self.register_host_syscall_with_cost(id, 0, syscall);
+    }
+
+    pub fn register_host_syscall_with_cost(
+        &mut self,
+        id: u16,
+        cost: u64,
+        syscall: impl FnMut(&mut VM<H>, &mut H) -> bool + Send + 'static,
+    ) {
+        self.insert_syscall(
+            id,
+            cost,
+            SyscallHandler::Host(Arc::new(Mutex::new(syscall))),
+        );
+    }
+
+    fn insert_syscall(&mut self, id: u16, cost: u64, handler: SyscallHandler<H>) {
+        self.syscalls.insert(id, Syscall { handler, cost });
+    }
+}
+
+#[cfg(test)]
+mod vm_tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let vm = VM::new();
+        assert_eq!(vm.pc, 0);
+        assert_eq!(vm.registers, [0; 256]);
+        assert!(!vm.comparison);
+        assert_eq!(vm.code, Vec::new());
+        assert_eq!(vm.memory.len(), DEFAULT_MEMORY_SIZE);
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::STOP);
+        vm.run().unwrap();
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn test_load() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(76);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 76);
+    }
+
+    #[test]
+    fn test_mov() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(128);
+        vm.write_opcode(OpCode::MOV);
+        vm.write_u8(1);
+        vm.write_u8(0);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 128);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(42);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(23);
+        vm.write_opcode(OpCode::ADD);
+        vm.write_u8(2);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 65);
+    }
+
+    #[test]
+    fn test_sub() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(42);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(18);
+        vm.write_opcode(OpCode::SUB);
+        vm.write_u8(2);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 24);
+    }
+
+    #[test]
+    fn test_mul() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(6);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(8);
+        vm.write_opcode(OpCode::MUL);
+        vm.write_u8(2);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 48);
+    }
+
+    #[test]
+    fn test_div() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(48);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(6);
+        vm.write_opcode(OpCode::DIV);
+        vm.write_u8(2);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 8);
+    }
+
+    #[test]
+    fn test_jmp() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(7); // 2, 3
+        vm.write_opcode(OpCode::JMP); // 4
+        vm.write_u8(0); // 5
+        vm.write_opcode(OpCode::STOP); // 6
+        vm.write_opcode(OpCode::LOAD); // 7
+        vm.write_u8(1); // 8
+        vm.write_u16(111); // 9, 10
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 111); // JMP should skip the STOP instruction, allowing the LOAD to execute
+    }
+
+    #[test]
+    fn test_jfw() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(1); // 2, 3
+        vm.write_opcode(OpCode::JFW); // 4
+        vm.write_u8(0); // 5
+        vm.write_opcode(OpCode::STOP); // 6
+        vm.write_opcode(OpCode::LOAD); // 7
+        vm.write_u8(1); // 8
+        vm.write_u16(111); // 9, 10
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 111); // JMPF should skip the STOP instruction, allowing the LOAD to execute
+    }
+
+    #[test]
+    fn test_jbk() {
+        // We will skip over a LOAD instruction and then jump back to it to test JMPB
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(15); // 2, 3
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(1); // 5
+        vm.write_u16(7); // 6, 7
+        vm.write_opcode(OpCode::JMP); // 8
+        vm.write_u8(0); // 9
+        vm.write_opcode(OpCode::LOAD); // 10
+        vm.write_u8(1); // 11
+        vm.write_u16(111); // 12, 13
+        vm.write_opcode(OpCode::STOP); // 14
+        vm.write_opcode(OpCode::JBK); // 15
+        vm.write_u8(1); // 16
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 111); // JMPB should go back to the LOAD instruction at 10
+    }
+
+    #[test]
+    fn test_eq() {
+        let mut vm = VM::new();
+
+        // Test equality = true
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::EQ);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(vm.comparison);
+
+        // Test equality = false
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(12);
+        vm.write_opcode(OpCode::EQ);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(!vm.comparison);
+    }
+
+    #[test]
+    fn test_neq() {
+        let mut vm = VM::new();
+
+        // Test inequality = true
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(14);
+        vm.write_opcode(OpCode::NEQ);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(vm.comparison);
+
+        // Test inequality = false
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(14);
+        vm.write_opcode(OpCode::NEQ);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(!vm.comparison);
+    }
+
+    #[test]
+    fn test_gt() {
+        let mut vm = VM::new();
+
+        // Test greater than = true
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(14);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::GT);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(vm.comparison);
+
+        // Test greater than = false
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::GT);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(!vm.comparison);
+    }
+
+    #[test]
+    fn test_lt() {
+        let mut vm = VM::new();
+
+        // Test less than = true
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(14);
+        vm.write_opcode(OpCode::LT);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(vm.comparison);
+
+        // Test less than = false
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(15);
+        vm.write_opcode(OpCode::LT);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(!vm.comparison);
+    }
+
+    #[test]
+    fn test_gte() {
+        let mut vm = VM::new();
+
+        // Test greater than or equal = true
+        // Greater than
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(14);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::GTE);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(vm.comparison);
+
+        // Equal to
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::GTE);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(vm.comparison);
+
+        // Test greater than or equal = false
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(12);
+        vm.write_opcode(OpCode::GTE);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(!vm.comparison);
+    }
+
+    #[test]
+    fn test_lte() {
+        let mut vm = VM::new();
+
+        // Test less than or equal = true
+        // Less than
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(14);
+        vm.write_opcode(OpCode::LTE);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(vm.comparison);
+
+        // Equal to
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(13);
+        vm.write_opcode(OpCode::LTE);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(vm.comparison);
+
+        // Test less than or equal = false
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(15);
+        vm.write_opcode(OpCode::LTE);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.run().unwrap();
+        assert!(!vm.comparison);
+    }
+
+    #[test]
+    fn test_jeq() {
+        let mut vm = VM::new();
+
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(13); // 2, 3
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(1); // 5
+        vm.write_u16(13); // 6, 7
+        vm.write_opcode(OpCode::LOAD); // 8
+        vm.write_u8(2); // 9
+        vm.write_u16(18); // 10, 11
+        vm.write_opcode(OpCode::EQ); // 12
+        vm.write_u8(0); // 13
+        vm.write_u8(1); // 14
+        vm.write_opcode(OpCode::JEQ); // 15
+        vm.write_u8(2); // 16
+        vm.write_opcode(OpCode::STOP); // 17
+        vm.write_opcode(OpCode::LOAD); // 18
+        vm.write_u8(3); // 19
+        vm.write_u16(14); // 20, 21
+        vm.run().unwrap();
+
+        assert!(vm.comparison);
+        assert_eq!(vm.registers[3], 14); // Should have jumped to 18 and skipped over the stop instruction
+    }
+
+    #[test]
+    fn test_jne() {
+        let mut vm = VM::new();
+
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(13); // 2, 3
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(1); // 5
+        vm.write_u16(13); // 6, 7
+        vm.write_opcode(OpCode::LOAD); // 8
+        vm.write_u8(2); // 9
+        vm.write_u16(18); // 10, 11
+        vm.write_opcode(OpCode::EQ); // 12
+        vm.write_u8(0); // 13
+        vm.write_u8(1); // 14
+        vm.write_opcode(OpCode::JNE); // 15
+        vm.write_u8(2); // 16
+        vm.write_opcode(OpCode::STOP); // 17
+        vm.write_opcode(OpCode::LOAD); // 18
+        vm.write_u8(3); // 19
+        vm.write_u16(14); // 20, 21
+        vm.run().unwrap();
+
+        assert!(vm.comparison);
+        assert_ne!(vm.registers[3], 14); // Should have jumped to 14 and executed the stop instruction, never loading 14 into register 3
+    }
+
+    #[test]
+    fn test_sys() {
+        let mut vm = VM::new();
+
+        vm.register_syscall(0, |vm| {
+            vm.registers[0] = 321;
+
+            true
+        });
+
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], 321);
+    }
+
+    #[test]
+    fn test_halt_reasons() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(1);
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+
+        vm.write_opcode(OpCode::STOP);
+        assert_eq!(vm.run(), Ok(HaltReason::Stopped));
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::STOP);
+        vm.write_u8(200);
+        vm.pc = 1;
+        assert_eq!(vm.run(), Err(VmError::UnknownOpcode { pc: 1, byte: 200 }));
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn test_unknown_syscall() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(7);
+        assert_eq!(vm.run(), Err(VmError::UnknownSyscall { id: 7, pc: 0 }));
+    }
+
+    #[test]
+    fn test_syscall_failed() {
+        let mut vm = VM::new();
+        vm.register_syscall(3, |_| false);
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(3);
+        assert_eq!(vm.run(), Err(VmError::SyscallFailed { id: 3, pc: 0 }));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(48); // 2, 3
+        vm.write_opcode(OpCode::DIV); // 4
+        vm.write_u8(2); // 5
+        vm.write_u8(0); // 6
+        vm.write_u8(1); // 7
+        assert_eq!(vm.run(), Err(VmError::DivisionByZero { pc: 4 }));
+        assert_eq!(vm.pc, 4);
+    }
+
+    #[test]
+    fn test_arithmetic_overflow() {
+        let mut vm = VM::new();
+        vm.registers[0] = i64::MAX;
+        vm.registers[1] = 1;
+        vm.write_opcode(OpCode::ADD);
+        vm.write_u8(2);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        assert_eq!(vm.run(), Err(VmError::ArithmeticOverflow { pc: 0 }));
+
+        let mut vm = VM::new();
+        vm.registers[0] = i64::MIN;
+        vm.registers[1] = -1;
+        vm.write_opcode(OpCode::DIV);
+        vm.write_u8(2);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        assert_eq!(vm.run(), Err(VmError::ArithmeticOverflow { pc: 0 }));
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        assert_eq!(vm.run(), Err(VmError::TruncatedInstruction { pc: 0 }));
+    }
+
+    #[test]
+    fn test_jump_out_of_bounds() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(10); // 2, 3
+        vm.write_opcode(OpCode::JBK); // 4
+        vm.write_u8(0); // 5
+        assert_eq!(
+            vm.run(),
+            Err(VmError::JumpOutOfBounds { pc: 4, target: -4 })
+        );
+
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(100); // 2, 3
+        vm.write_opcode(OpCode::JMP); // 4
+        vm.write_u8(0); // 5
+        assert_eq!(
+            vm.run(),
+            Err(VmError::JumpOutOfBounds { pc: 4, target: 100 })
+        );
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(5);
+        vm.write_opcode(OpCode::STOP);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(6);
+        vm.write_u8(200);
+
+        assert_eq!(vm.step(), ExecutionState::Running);
+        assert_eq!(vm.registers[0], 5);
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.step(), ExecutionState::Halted(HaltReason::Stopped));
+        assert_eq!(vm.step(), ExecutionState::Running);
+        assert_eq!(vm.registers[1], 6);
+        assert_eq!(
+            vm.step(),
+            ExecutionState::Trapped(VmError::UnknownOpcode { pc: 9, byte: 200 })
+        );
+        assert_eq!(vm.pc, 9);
+
+        vm.pc = 10;
+        assert_eq!(vm.step(), ExecutionState::Halted(HaltReason::EndOfCode));
+    }
+
+    #[test]
+    fn test_yield() {
+        let mut vm = VM::new();
+        vm.register_syscall(0, |vm| {
+            vm.registers[0] += 1;
+            vm.yield_execution();
+
+            true
+        });
+
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+
+        assert_eq!(vm.run(), Ok(HaltReason::Yielded));
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.pc, 3);
+        assert_eq!(vm.run(), Ok(HaltReason::Yielded));
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(1); // 2, 3
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(1); // 5
+        vm.write_u16(2); // 6, 7
+        vm.write_opcode(OpCode::LOAD); // 8
+        vm.write_u8(2); // 9
+        vm.write_u16(3); // 10, 11
+
+        assert_eq!(vm.run_until(8), Ok(HaltReason::ReachedTarget));
+        assert_eq!(vm.registers[1], 2);
+        assert_eq!(vm.registers[2], 0);
+        assert_eq!(vm.run_until(8), Ok(HaltReason::EndOfCode));
+        assert_eq!(vm.registers[2], 3);
+    }
+
+    #[test]
+    fn test_out_of_fuel() {
+        // Infinite loop, jumping back to the start forever
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(0); // 2, 3
+        vm.write_opcode(OpCode::JMP); // 4
+        vm.write_u8(0); // 5
+
+        assert_eq!(vm.run_with_fuel(5), Ok(HaltReason::OutOfFuel));
+        assert_eq!(vm.fuel(), Some(0));
+        assert_eq!(vm.pc, 4);
+
+        vm.refuel(2);
+        assert_eq!(vm.run(), Ok(HaltReason::OutOfFuel));
+        assert_eq!(vm.pc, 4);
+    }
+
+    #[test]
+    fn test_gas_schedule() {
+        let mut vm = VM::new();
+        vm.gas_schedule.set_cost(OpCode::LOAD, 3);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(1);
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(1);
+        vm.write_u16(2);
+
+        assert_eq!(vm.run_with_fuel(5), Ok(HaltReason::OutOfFuel));
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(vm.fuel(), Some(2));
+
+        // Resumes exactly at the instruction that could not be paid for
+        vm.refuel(1);
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+        assert_eq!(vm.registers[1], 2);
+        assert_eq!(vm.fuel(), Some(0));
+    }
+
+    #[test]
+    fn test_syscall_cost() {
+        let mut vm = VM::new();
+        vm.register_syscall_with_cost(0, 10, |vm| {
+            vm.registers[0] += 1;
+
+            true
+        });
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+
+        assert_eq!(vm.run_with_fuel(10), Ok(HaltReason::OutOfFuel));
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.run_with_fuel(11), Ok(HaltReason::EndOfCode));
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.fuel(), Some(0));
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(1); // 2, 3
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(1); // 5
+        vm.write_u16(2); // 6, 7
+        vm.add_breakpoint(4);
+
+        assert_eq!(vm.run(), Ok(HaltReason::BreakpointHit { pc: 4 }));
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 0);
+
+        // Resuming executes the instruction under the breakpoint
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+        assert_eq!(vm.registers[1], 2);
+
+        // The breakpoint triggers again on the next visit
+        vm.pc = 0;
+        assert_eq!(vm.run(), Ok(HaltReason::BreakpointHit { pc: 4 }));
+        assert!(vm.remove_breakpoint(4));
+        vm.pc = 0;
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        // Loops forever incrementing %1
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(1); // 2, 3
+        vm.write_opcode(OpCode::LOAD); // 4
+        vm.write_u8(2); // 5
+        vm.write_u16(6); // 6, 7
+        vm.write_opcode(OpCode::ADD); // 8
+        vm.write_u8(1); // 9
+        vm.write_u8(1); // 10
+        vm.write_u8(0); // 11
+        vm.write_opcode(OpCode::JBK); // 12
+        vm.write_u8(2); // 13
+        vm.watch_register(1, Watchpoint::Write);
 
-    // Used for reading OpCodes and register numbers
-    fn read_u8(&mut self) -> u8 {
-        let result = self.code[self.pc];
-        self.pc += 1;
-        result
+        assert_eq!(
+            vm.run(),
+            Ok(HaltReason::WatchpointHit {
+                register: 1,
+                value: 1,
+                pc: 8
+            })
+        );
+        assert_eq!(vm.pc, 12);
+        assert_eq!(
+            vm.run(),
+            Ok(HaltReason::WatchpointHit {
+                register: 1,
+                value: 2,
+                pc: 8
+            })
+        );
+
+        vm.watch_register(1, Watchpoint::WriteValue(10));
+        assert_eq!(
+            vm.run(),
+            Ok(HaltReason::WatchpointHit {
+                register: 1,
+                value: 10,
+                pc: 8
+            })
+        );
+
+        assert!(vm.unwatch_register(1));
+        assert_eq!(vm.run_with_fuel(20), Ok(HaltReason::OutOfFuel));
+        assert_eq!(vm.registers[1], 20);
     }
 
-    // Used for reading numeric values (should only be used in the LOAD instruction)
-    fn read_u16(&mut self) -> u16 {
-        let result = ((self.code[self.pc] as u16) << 8) | self.code[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+    // Jumps back to the start forever
+    fn infinite_loop() -> VM {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(0);
+        vm.write_opcode(OpCode::JMP);
+        vm.write_u8(0);
+        vm
     }
 
-    pub fn write_opcode(&mut self, opcode: OpCode) {
-        self.code.push(opcode as u8);
+    #[test]
+    fn test_interrupt_handle() {
+        let mut vm = infinite_loop();
+        let handle = vm.interrupt_handle();
+
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            handle.interrupt();
+        });
+
+        assert_eq!(vm.run(), Ok(HaltReason::Interrupted));
+        thread.join().unwrap();
+
+        // The interrupt is consumed, so the VM can be resumed
+        assert_eq!(vm.run_with_fuel(10), Ok(HaltReason::OutOfFuel));
     }
 
-    pub fn write_u8(&mut self, value: u8) {
-        self.code.push(value);
+    #[test]
+    fn test_interrupt_handle_of_clone() {
+        let mut vm = infinite_loop();
+        vm.interrupt_handle().interrupt();
+
+        // Interrupting a VM does not stop its clones, which have their own handle
+        let mut clone = vm.clone();
+        assert_eq!(clone.run_with_fuel(10), Ok(HaltReason::OutOfFuel));
+        assert_eq!(vm.run(), Ok(HaltReason::Interrupted));
+
+        clone.interrupt_handle().interrupt();
+        assert_eq!(vm.run_with_fuel(10), Ok(HaltReason::OutOfFuel));
+        assert_eq!(clone.run(), Ok(HaltReason::Interrupted));
     }
 
-    pub fn write_u16(&mut self, value: u16) {
-        self.code.push((value >> 8) as u8);
-        self.code.push(value as u8);
+    #[test]
+    fn test_run_on_worker_thread() {
+        fn assert_send<T: Send>() {}
+        assert_send::<VM>();
+
+        let mut vm = infinite_loop();
+        vm.register_syscall(0, |_| true);
+        vm.attach_device(0x8000, crate::device::TimerDevice::new(0))
+            .unwrap();
+        let handle = vm.interrupt_handle();
+
+        let worker = std::thread::spawn(move || {
+            let result = vm.run();
+            (vm, result)
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        handle.interrupt();
+
+        let (mut vm, result) = worker.join().unwrap();
+        assert_eq!(result, Ok(HaltReason::Interrupted));
+        assert_eq!(vm.run_with_fuel(10), Ok(HaltReason::OutOfFuel));
     }
 
-    pub fn register_syscall(&mut self, id: u16, syscall: fn(&mut VM) -> bool) {
-        self.syscalls.insert(id, syscall);
+    #[test]
+    fn test_run_with_deadline() {
+        let mut vm = infinite_loop();
+        assert_eq!(
+            vm.run_with_deadline(Duration::from_millis(10)),
+            Ok(HaltReason::Interrupted)
+        );
+        assert_eq!(vm.run_with_fuel(10), Ok(HaltReason::OutOfFuel));
     }
-}
 
-#[cfg(test)]
-mod vm_tests {
-    use super::*;
+    #[test]
+    fn test_capturing_syscall() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut vm = VM::new();
+
+        let buffer = output.clone();
+        let mut calls = 0;
+        vm.register_syscall(0, move |vm| {
+            calls += 1;
+            buffer
+                .lock()
+                .unwrap()
+                .push(format!("{}: {}", calls, vm.registers[0]));
+
+            true
+        });
+
+        vm.write_opcode(OpCode::LOAD);
+        vm.write_u8(0);
+        vm.write_u16(42);
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+        vm.run().unwrap();
+
+        // Clones share the syscall and the state it captured
+        let mut clone = vm.clone();
+        clone.pc = 4;
+        clone.run().unwrap();
+
+        assert_eq!(*output.lock().unwrap(), vec!["1: 42", "2: 42"]);
+    }
 
     #[test]
-    fn test_new() {
-        let vm = VM::new();
-        assert_eq!(vm.pc, 0);
-        assert_eq!(vm.registers, [0; 256]);
-        assert_eq!(vm.comparison, false);
-        assert_eq!(vm.code, Vec::new());
+    fn test_syscall_on_cloned_vms() {
+        let mut vm = VM::new();
+        vm.register_syscall(0, |_| {
+            std::thread::sleep(Duration::from_millis(50));
+            true
+        });
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+
+        // Clones running the same syscall at the same time wait for each other instead of failing
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut clone = vm.clone();
+                std::thread::spawn(move || clone.run())
+            })
+            .collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), Ok(HaltReason::EndOfCode));
+        }
     }
 
     #[test]
-    fn test_stop() {
+    fn test_reentrant_syscall() {
+        let nested = Arc::new(Mutex::new(None));
         let mut vm = VM::new();
-        vm.write_opcode(OpCode::STOP);
-        vm.run();
-        assert_eq!(vm.pc, 1);
+
+        let result = nested.clone();
+        vm.register_syscall(0, move |vm| {
+            if vm.registers[0] == 0 {
+                vm.registers[0] = 1;
+                let pc = vm.pc;
+                vm.pc = 0;
+                *result.lock().unwrap() = Some(vm.run());
+                vm.pc = pc;
+            }
+            true
+        });
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+
+        // Running the syscall again from its own handler fails instead of deadlocking
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+        assert_eq!(
+            *nested.lock().unwrap(),
+            Some(Err(VmError::SyscallFailed { id: 0, pc: 0 }))
+        );
     }
 
     #[test]
-    fn test_load() {
+    fn test_plain_function_syscall() {
+        fn set_register(vm: &mut VM) -> bool {
+            vm.registers[0] = 7;
+            true
+        }
+
         let mut vm = VM::new();
+        vm.register_syscall(0, set_register);
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_host_syscall() {
+        #[derive(Clone, Default)]
+        struct Entity {
+            health: i64,
+        }
+
+        let mut vm = VM::with_host(Entity { health: 100 });
+        vm.register_host_syscall(0, |vm, entity: &mut Entity| {
+            entity.health -= vm.registers[0];
+            vm.registers[1] = entity.health;
+
+            true
+        });
+        vm.register_syscall(1, |vm| {
+            vm.registers[2] = vm.host().health * 2;
+
+            true
+        });
+
         vm.write_opcode(OpCode::LOAD);
         vm.write_u8(0);
-        vm.write_u16(76);
-        vm.run();
-        assert_eq!(vm.registers[0], 76);
+        vm.write_u16(30);
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(0);
+        vm.write_opcode(OpCode::SYS);
+        vm.write_u16(1);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[1], 70);
+        assert_eq!(vm.registers[2], 140);
+        assert_eq!(vm.host().health, 70);
+
+        vm.host_mut().health = 5;
+        assert_eq!(vm.into_host().health, 5);
     }
 
     #[test]
-    fn test_mov() {
+    fn test_load_store() {
         let mut vm = VM::new();
-        vm.write_opcode(OpCode::LOAD);
+        vm.registers[0] = 100; // Address
+        vm.registers[1] = 0x0102_0304_0506_0708;
+
+        vm.write_opcode(OpCode::ST64);
         vm.write_u8(0);
-        vm.write_u16(128);
-        vm.write_opcode(OpCode::MOV);
         vm.write_u8(1);
+        vm.write_opcode(OpCode::LD64);
+        vm.write_u8(2);
         vm.write_u8(0);
-        vm.run();
-        assert_eq!(vm.registers[1], 128);
+        vm.write_opcode(OpCode::LD32);
+        vm.write_u8(3);
+        vm.write_u8(0);
+        vm.write_opcode(OpCode::LD16);
+        vm.write_u8(4);
+        vm.write_u8(0);
+        vm.write_opcode(OpCode::LD8);
+        vm.write_u8(5);
+        vm.write_u8(0);
+        vm.run().unwrap();
+
+        assert_eq!(vm.read_memory(100, 8), Some(&[1, 2, 3, 4, 5, 6, 7, 8][..]));
+        assert_eq!(vm.registers[2], 0x0102_0304_0506_0708);
+        assert_eq!(vm.registers[3], 0x0102_0304);
+        assert_eq!(vm.registers[4], 0x0102);
+        assert_eq!(vm.registers[5], 0x01);
     }
 
     #[test]
-    fn test_add() {
+    fn test_narrow_store() {
         let mut vm = VM::new();
-        vm.write_opcode(OpCode::LOAD);
+        vm.registers[0] = 10;
+        vm.registers[1] = -1;
+
+        vm.write_opcode(OpCode::ST8);
         vm.write_u8(0);
-        vm.write_u16(42);
-        vm.write_opcode(OpCode::LOAD);
         vm.write_u8(1);
-        vm.write_u16(23);
-        vm.write_opcode(OpCode::ADD);
+        vm.write_opcode(OpCode::ST16);
+        vm.write_u8(0);
+        vm.write_u8(1);
+        vm.write_opcode(OpCode::LD32);
         vm.write_u8(2);
         vm.write_u8(0);
+        vm.run().unwrap();
+
+        // Narrow loads zero-extend instead of sign-extending
+        assert_eq!(vm.read_memory(10, 3), Some(&[0xff, 0xff, 0][..]));
+        assert_eq!(vm.registers[2], 0xffff_0000);
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let mut vm = VM::new();
+        vm.memory.resize(16, 0);
+        vm.registers[0] = 12;
+        vm.write_opcode(OpCode::LD32);
+        vm.write_u8(1);
+        vm.write_u8(0);
+        vm.write_opcode(OpCode::LD64);
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.registers[2], 65);
+        vm.write_u8(0);
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::MemoryOutOfBounds {
+                pc: 3,
+                address: 12,
+                width: 8
+            })
+        );
+
+        let mut vm = VM::new();
+        vm.registers[0] = -1;
+        vm.write_opcode(OpCode::ST8);
+        vm.write_u8(0);
+        vm.write_u8(0);
+        assert_eq!(
+            vm.run(),
+            Err(VmError::MemoryOutOfBounds {
+                pc: 0,
+                address: -1,
+                width: 1
+            })
+        );
     }
 
     #[test]
-    fn test_sub() {
+    fn test_host_memory_access() {
         let mut vm = VM::new();
-        vm.write_opcode(OpCode::LOAD);
+        vm.memory.resize(4, 0);
+
+        assert!(vm.write_memory(1, &[7, 8]));
+        assert!(!vm.write_memory(3, &[7, 8]));
+        assert_eq!(vm.read_memory(0, 4), Some(&[0, 7, 8, 0][..]));
+        assert_eq!(vm.read_memory(2, 3), None);
+        assert_eq!(vm.read_memory(usize::MAX, 2), None);
+    }
+
+    #[test]
+    fn test_call_ret() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOAD); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(7); // 2, 3
+        vm.write_opcode(OpCode::CALL); // 4
+        vm.write_u8(0); // 5
+        vm.write_opcode(OpCode::STOP); // 6
+        vm.write_opcode(OpCode::LOAD); // 7
+        vm.write_u8(1); // 8
+        vm.write_u16(111); // 9, 10
+        vm.write_opcode(OpCode::RET); // 11
+        vm.add_breakpoint(11);
+
+        assert_eq!(vm.run(), Ok(HaltReason::BreakpointHit { pc: 11 }));
+        assert_eq!(
+            vm.call_frames(),
+            &[CallFrame {
+                call_site: 4,
+                target: 7,
+                return_address: 6,
+                interrupt: None
+            }]
+        );
+        assert_eq!(vm.backtrace(), vec![11, 4]);
+
+        assert_eq!(vm.run(), Ok(HaltReason::Stopped));
+        assert_eq!(vm.registers[1], 111);
+        assert_eq!(vm.pc, 7);
+        assert!(vm.call_frames().is_empty());
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut vm = VM::new();
+        vm.registers[0] = 1;
+        vm.registers[1] = 2;
+        vm.write_opcode(OpCode::PUSH);
         vm.write_u8(0);
-        vm.write_u16(42);
-        vm.write_opcode(OpCode::LOAD);
+        vm.write_opcode(OpCode::PUSH);
         vm.write_u8(1);
-        vm.write_u16(18);
-        vm.write_opcode(OpCode::SUB);
-        vm.write_u8(2);
+        vm.write_opcode(OpCode::POP);
         vm.write_u8(0);
+        vm.write_opcode(OpCode::POP);
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.registers[2], 24);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.registers[1], 1);
+        assert!(vm.stack.is_empty());
     }
 
     #[test]
-    fn test_mul() {
+    fn test_stack_overflow() {
+        // Recurses forever
         let mut vm = VM::new();
-        vm.write_opcode(OpCode::LOAD);
+        vm.call_depth_limit = 8;
+        vm.write_opcode(OpCode::CALL);
+        vm.write_u8(0);
+        assert_eq!(vm.run(), Err(VmError::StackOverflow { pc: 0 }));
+        assert_eq!(vm.call_frames().len(), 8);
+
+        let mut vm = VM::new();
+        vm.stack_limit = 1;
+        vm.write_opcode(OpCode::PUSH);
+        vm.write_u8(0);
+        vm.write_opcode(OpCode::PUSH);
+        vm.write_u8(0);
+        assert_eq!(vm.run(), Err(VmError::StackOverflow { pc: 2 }));
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::RET);
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow { pc: 0 }));
+
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::POP);
+        vm.write_u8(0);
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow { pc: 0 }));
+    }
+
+    // Compares %0 with %1 and runs a branch, returning whether it was taken
+    fn branch_taken(a: i64, b: i64, branch: OpCode) -> bool {
+        let mut vm = VM::new();
+        vm.registers[0] = a;
+        vm.registers[1] = b;
+        vm.registers[2] = 6;
+        vm.write_opcode(OpCode::EQ); // 0
+        vm.write_u8(0); // 1
+        vm.write_u8(1); // 2
+        vm.write_opcode(branch); // 3
+        vm.write_u8(2); // 4
+        vm.write_opcode(OpCode::STOP); // 5
+        vm.run().unwrap() == HaltReason::EndOfCode
+    }
+
+    #[test]
+    fn test_signed_branches() {
+        assert!(branch_taken(-5, 3, OpCode::JLT));
+        assert!(!branch_taken(3, -5, OpCode::JLT));
+        assert!(!branch_taken(3, 3, OpCode::JLT));
+        assert!(branch_taken(i64::MIN, 1, OpCode::JLT));
+        assert!(branch_taken(3, 3, OpCode::JGE));
+        assert!(branch_taken(i64::MAX, -1, OpCode::JGE));
+        assert!(branch_taken(4, 3, OpCode::JGT));
+        assert!(!branch_taken(3, 3, OpCode::JGT));
+        assert!(branch_taken(3, 3, OpCode::JLE));
+        assert!(!branch_taken(4, 3, OpCode::JLE));
+        assert!(branch_taken(7, 7, OpCode::JZ));
+        assert!(branch_taken(7, 8, OpCode::JNZ));
+    }
+
+    #[test]
+    fn test_carry_overflow_branches() {
+        // Unsigned 1 - 2 borrows
+        assert!(branch_taken(1, 2, OpCode::JC));
+        assert!(branch_taken(2, 1, OpCode::JNC));
+        // Signed MIN - 1 overflows
+        assert!(branch_taken(i64::MIN, 1, OpCode::JO));
+        assert!(branch_taken(0, 1, OpCode::JNO));
+    }
+
+    #[test]
+    fn test_arithmetic_flags() {
+        let mut vm = VM::new();
+        vm.registers[0] = -1;
+        vm.registers[1] = 1;
+        vm.write_opcode(OpCode::ADD);
+        vm.write_u8(2);
         vm.write_u8(0);
-        vm.write_u16(6);
-        vm.write_opcode(OpCode::LOAD);
         vm.write_u8(1);
-        vm.write_u16(8);
-        vm.write_opcode(OpCode::MUL);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], 0);
+        assert_eq!(
+            vm.flags,
+            Flags {
+                zero: true,
+                negative: false,
+                carry: true,
+                overflow: false
+            }
+        );
+
+        vm.write_opcode(OpCode::SUB);
+        vm.write_u8(2);
+        vm.write_u8(1);
+        vm.write_u8(1);
+        vm.write_opcode(OpCode::SUB);
         vm.write_u8(2);
         vm.write_u8(0);
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.registers[2], 48);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], -2);
+        assert_eq!(
+            vm.flags,
+            Flags {
+                zero: false,
+                negative: true,
+                carry: false,
+                overflow: false
+            }
+        );
     }
 
-    #[test]
-    fn test_div() {
+    // Runs a single three-register instruction on a and b, returning the result
+    fn calculate(opcode: OpCode, mode: ArithmeticMode, a: i64, b: i64) -> Result<i64, VmError> {
         let mut vm = VM::new();
-        vm.write_opcode(OpCode::LOAD);
+        vm.arithmetic_mode = mode;
+        vm.registers[0] = a;
+        vm.registers[1] = b;
+        vm.write_opcode(opcode);
+        vm.write_u8(2);
         vm.write_u8(0);
-        vm.write_u16(48);
-        vm.write_opcode(OpCode::LOAD);
         vm.write_u8(1);
-        vm.write_u16(6);
-        vm.write_opcode(OpCode::DIV);
+        vm.run().map(|_| vm.registers[2])
+    }
+
+    #[test]
+    fn test_arithmetic_modes() {
+        use ArithmeticMode::*;
+
+        let overflow = Err(VmError::ArithmeticOverflow { pc: 0 });
+        assert_eq!(calculate(OpCode::ADD, Checked, i64::MAX, 1), overflow);
+        assert_eq!(calculate(OpCode::ADD, Wrapping, i64::MAX, 1), Ok(i64::MIN));
+        assert_eq!(
+            calculate(OpCode::ADD, Saturating, i64::MAX, 1),
+            Ok(i64::MAX)
+        );
+        assert_eq!(
+            calculate(OpCode::ADD, Saturating, i64::MIN, -1),
+            Ok(i64::MIN)
+        );
+
+        assert_eq!(calculate(OpCode::SUB, Checked, i64::MIN, 1), overflow);
+        assert_eq!(calculate(OpCode::SUB, Wrapping, i64::MIN, 1), Ok(i64::MAX));
+        assert_eq!(
+            calculate(OpCode::SUB, Saturating, i64::MIN, 1),
+            Ok(i64::MIN)
+        );
+        assert_eq!(
+            calculate(OpCode::SUB, Saturating, 0, i64::MIN),
+            Ok(i64::MAX)
+        );
+
+        assert_eq!(calculate(OpCode::MUL, Checked, i64::MAX, 2), overflow);
+        assert_eq!(calculate(OpCode::MUL, Wrapping, i64::MAX, 2), Ok(-2));
+        assert_eq!(
+            calculate(OpCode::MUL, Saturating, i64::MAX, 2),
+            Ok(i64::MAX)
+        );
+        assert_eq!(
+            calculate(OpCode::MUL, Saturating, i64::MIN, 2),
+            Ok(i64::MIN)
+        );
+
+        assert_eq!(calculate(OpCode::DIV, Checked, i64::MIN, -1), overflow);
+        assert_eq!(calculate(OpCode::DIV, Wrapping, i64::MIN, -1), Ok(i64::MIN));
+        assert_eq!(
+            calculate(OpCode::DIV, Saturating, i64::MIN, -1),
+            Ok(i64::MAX)
+        );
+        assert_eq!(
+            calculate(OpCode::DIV, Wrapping, 1, 0),
+            Err(VmError::DivisionByZero { pc: 0 })
+        );
+
+        // Results that fit are the same in every mode
+        for mode in [Checked, Wrapping, Saturating] {
+            assert_eq!(calculate(OpCode::ADD, mode, i64::MAX - 1, 1), Ok(i64::MAX));
+            assert_eq!(calculate(OpCode::SUB, mode, i64::MIN + 1, 1), Ok(i64::MIN));
+            assert_eq!(calculate(OpCode::MUL, mode, -1, i64::MAX), Ok(-i64::MAX));
+        }
+    }
+
+    #[test]
+    fn test_explicit_mode_opcodes() {
+        // Explicit opcodes ignore the VM's arithmetic mode
+        let overflow = Err(VmError::ArithmeticOverflow { pc: 0 });
+        let mode = ArithmeticMode::Wrapping;
+        assert_eq!(calculate(OpCode::ADDT, mode, i64::MAX, 1), overflow);
+        assert_eq!(calculate(OpCode::SUBT, mode, i64::MIN, 1), overflow);
+        assert_eq!(calculate(OpCode::MULT, mode, i64::MIN, -1), overflow);
+
+        let mode = ArithmeticMode::Checked;
+        assert_eq!(calculate(OpCode::ADDW, mode, i64::MAX, 1), Ok(i64::MIN));
+        assert_eq!(calculate(OpCode::SUBW, mode, i64::MIN, 1), Ok(i64::MAX));
+        assert_eq!(calculate(OpCode::MULW, mode, i64::MIN, -1), Ok(i64::MIN));
+        assert_eq!(calculate(OpCode::ADDS, mode, i64::MAX, 1), Ok(i64::MAX));
+        assert_eq!(calculate(OpCode::SUBS, mode, i64::MIN, 1), Ok(i64::MIN));
+        assert_eq!(calculate(OpCode::MULS, mode, i64::MIN, -1), Ok(i64::MAX));
+    }
+
+    #[test]
+    fn test_overflow_flag() {
+        let mut vm = VM::new();
+        vm.arithmetic_mode = ArithmeticMode::Wrapping;
+        vm.registers[0] = i64::MAX;
+        vm.registers[1] = 1;
+        vm.write_opcode(OpCode::ADD);
         vm.write_u8(2);
         vm.write_u8(0);
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.registers[2], 8);
+        vm.run().unwrap();
+
+        assert!(vm.flags.overflow);
+        assert!(vm.flags.negative);
+        assert!(!vm.flags.carry);
     }
 
     #[test]
-    fn test_jmp() {
+    fn test_bitwise() {
+        let mode = ArithmeticMode::Checked;
+        assert_eq!(calculate(OpCode::AND, mode, 0b1100, 0b1010), Ok(0b1000));
+        assert_eq!(calculate(OpCode::OR, mode, 0b1100, 0b1010), Ok(0b1110));
+        assert_eq!(calculate(OpCode::XOR, mode, 0b1100, 0b1010), Ok(0b0110));
+        assert_eq!(calculate(OpCode::AND, mode, -1, i64::MIN), Ok(i64::MIN));
+
         let mut vm = VM::new();
-        vm.write_opcode(OpCode::LOAD); // 0
-        vm.write_u8(0); // 1
-        vm.write_u16(7); // 2, 3
-        vm.write_opcode(OpCode::JMP); // 4
-        vm.write_u8(0); // 5
-        vm.write_opcode(OpCode::STOP); // 6
-        vm.write_opcode(OpCode::LOAD); // 7
-        vm.write_u8(1); // 8
-        vm.write_u16(111); // 9, 10
-        vm.run();
-        assert_eq!(vm.registers[1], 111); // JMP should skip the STOP instruction, allowing the LOAD to execute
+        vm.registers[0] = 0b1010;
+        vm.write_opcode(OpCode::NOT);
+        vm.write_u8(1);
+        vm.write_u8(0);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], -11);
+        assert!(vm.flags.negative);
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mode = ArithmeticMode::Checked;
+        assert_eq!(calculate(OpCode::SHL, mode, 3, 4), Ok(48));
+        assert_eq!(calculate(OpCode::SHL, mode, 1, 63), Ok(i64::MIN));
+        assert_eq!(calculate(OpCode::SHL, mode, 1, 64), Ok(0));
+        assert_eq!(calculate(OpCode::SHL, mode, 1, -1), Ok(0));
+
+        assert_eq!(calculate(OpCode::SHR, mode, 48, 4), Ok(3));
+        assert_eq!(calculate(OpCode::SHR, mode, -1, 60), Ok(0xf));
+        assert_eq!(calculate(OpCode::SHR, mode, -1, 64), Ok(0));
+        assert_eq!(calculate(OpCode::SHR, mode, -1, i64::MAX), Ok(0));
+
+        assert_eq!(calculate(OpCode::SAR, mode, 48, 4), Ok(3));
+        assert_eq!(calculate(OpCode::SAR, mode, -48, 4), Ok(-3));
+        assert_eq!(calculate(OpCode::SAR, mode, -1, 60), Ok(-1));
+        assert_eq!(calculate(OpCode::SAR, mode, -5, 64), Ok(-1));
+        assert_eq!(calculate(OpCode::SAR, mode, 5, 100), Ok(0));
+        assert_eq!(calculate(OpCode::SAR, mode, i64::MIN, -1), Ok(-1));
+    }
+
+    #[test]
+    fn test_mod() {
+        let mode = ArithmeticMode::Checked;
+        assert_eq!(calculate(OpCode::MOD, mode, 7, 3), Ok(1));
+        assert_eq!(calculate(OpCode::MOD, mode, -7, 3), Ok(-1));
+        assert_eq!(calculate(OpCode::MOD, mode, 7, -3), Ok(1));
+        assert_eq!(calculate(OpCode::MOD, mode, -7, -3), Ok(-1));
+        assert_eq!(calculate(OpCode::MOD, mode, i64::MIN, -1), Ok(0));
+        assert_eq!(
+            calculate(OpCode::MOD, mode, 7, 0),
+            Err(VmError::DivisionByZero { pc: 0 })
+        );
     }
 
     #[test]
-    fn test_jfw() {
+    fn test_min_max() {
+        let mode = ArithmeticMode::Checked;
+        assert_eq!(calculate(OpCode::MIN, mode, -7, 3), Ok(-7));
+        assert_eq!(
+            calculate(OpCode::MIN, mode, i64::MAX, i64::MIN),
+            Ok(i64::MIN)
+        );
+        assert_eq!(calculate(OpCode::MAX, mode, -7, 3), Ok(3));
+        assert_eq!(calculate(OpCode::MAX, mode, 3, 3), Ok(3));
+    }
+
+    // Runs a single two-register instruction on a, returning the result
+    fn unary(opcode: OpCode, mode: ArithmeticMode, a: i64) -> Result<i64, VmError> {
         let mut vm = VM::new();
-        vm.write_opcode(OpCode::LOAD); // 0
-        vm.write_u8(0); // 1
-        vm.write_u16(1); // 2, 3
-        vm.write_opcode(OpCode::JFW); // 4
-        vm.write_u8(0); // 5
-        vm.write_opcode(OpCode::STOP); // 6
-        vm.write_opcode(OpCode::LOAD); // 7
-        vm.write_u8(1); // 8
-        vm.write_u16(111); // 9, 10
-        vm.run();
-        assert_eq!(vm.registers[1], 111); // JMPF should skip the STOP instruction, allowing the LOAD to execute
+        vm.arithmetic_mode = mode;
+        vm.registers[0] = a;
+        vm.write_opcode(opcode);
+        vm.write_u8(1);
+        vm.write_u8(0);
+        vm.run().map(|_| vm.registers[1])
     }
 
     #[test]
-    fn test_jbk() {
-        // We will skip over a LOAD instruction and then jump back to it to test JMPB
-        let mut vm = VM::new();
-        vm.write_opcode(OpCode::LOAD); // 0
-        vm.write_u8(0); // 1
-        vm.write_u16(15); // 2, 3
-        vm.write_opcode(OpCode::LOAD); // 4
-        vm.write_u8(1); // 5
-        vm.write_u16(7); // 6, 7
-        vm.write_opcode(OpCode::JMP); // 8
-        vm.write_u8(0); // 9
-        vm.write_opcode(OpCode::LOAD); // 10
-        vm.write_u8(1); // 11
-        vm.write_u16(111); // 12, 13
-        vm.write_opcode(OpCode::STOP); // 14
-        vm.write_opcode(OpCode::JBK); // 15
-        vm.write_u8(1); // 16
-        vm.run();
-        assert_eq!(vm.registers[1], 111); // JMPB should go back to the LOAD instruction at 10
+    fn test_neg_abs() {
+        use ArithmeticMode::*;
+
+        let overflow = Err(VmError::ArithmeticOverflow { pc: 0 });
+        assert_eq!(unary(OpCode::NEG, Checked, 5), Ok(-5));
+        assert_eq!(unary(OpCode::NEG, Checked, -5), Ok(5));
+        assert_eq!(unary(OpCode::NEG, Checked, i64::MIN), overflow);
+        assert_eq!(unary(OpCode::NEG, Wrapping, i64::MIN), Ok(i64::MIN));
+        assert_eq!(unary(OpCode::NEG, Saturating, i64::MIN), Ok(i64::MAX));
+
+        assert_eq!(unary(OpCode::ABS, Checked, 5), Ok(5));
+        assert_eq!(unary(OpCode::ABS, Checked, -5), Ok(5));
+        assert_eq!(unary(OpCode::ABS, Checked, i64::MIN), overflow);
+        assert_eq!(unary(OpCode::ABS, Wrapping, i64::MIN), Ok(i64::MIN));
+        assert_eq!(unary(OpCode::ABS, Saturating, i64::MIN), Ok(i64::MAX));
     }
 
     #[test]
-    fn test_eq() {
+    fn test_inc_dec() {
         let mut vm = VM::new();
+        vm.registers[0] = 1;
+        vm.write_opcode(OpCode::INC);
+        vm.write_u8(0);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 2);
 
-        // Test equality = true
-        vm.write_opcode(OpCode::LOAD);
+        vm.write_opcode(OpCode::DEC);
         vm.write_u8(0);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(1);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::EQ);
+        vm.write_opcode(OpCode::DEC);
         vm.write_u8(0);
-        vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, true);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 0);
+        assert!(vm.flags.zero);
 
-        // Test equality = false
-        vm.write_opcode(OpCode::LOAD);
+        let mut vm = VM::new();
+        vm.registers[0] = i64::MAX;
+        vm.write_opcode(OpCode::INC);
         vm.write_u8(0);
-        vm.write_u16(12);
-        vm.write_opcode(OpCode::EQ);
+        assert_eq!(vm.run(), Err(VmError::ArithmeticOverflow { pc: 0 }));
+
+        vm.arithmetic_mode = ArithmeticMode::Wrapping;
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], i64::MIN);
+
+        vm.arithmetic_mode = ArithmeticMode::Saturating;
+        vm.write_opcode(OpCode::DEC);
         vm.write_u8(0);
-        vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, false);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], i64::MIN);
     }
 
     #[test]
-    fn test_neq() {
+    fn test_arithmetic_immediate() {
         let mut vm = VM::new();
-
-        // Test inequality = true
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(0);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::LOAD);
+        vm.registers[0] = 10;
+        vm.write_opcode(OpCode::ADDI);
         vm.write_u8(1);
-        vm.write_u16(14);
-        vm.write_opcode(OpCode::NEQ);
         vm.write_u8(0);
-        vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, true);
-
-        // Test inequality = false
-        vm.write_opcode(OpCode::LOAD);
+        vm.write_u16(-1i16 as u16);
+        vm.write_opcode(OpCode::SUBI);
+        vm.write_u8(2);
         vm.write_u8(0);
-        vm.write_u16(14);
-        vm.write_opcode(OpCode::NEQ);
+        vm.write_u16(12);
+        vm.write_opcode(OpCode::MULI);
+        vm.write_u8(3);
         vm.write_u8(0);
-        vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, false);
-    }
+        vm.write_u16(300);
+        vm.write_opcode(OpCode::ADDI);
+        vm.write_u8(4);
+        vm.write_u8(0);
+        vm.write_u16(i16::MAX as u16);
+        vm.write_opcode(OpCode::SUBI);
+        vm.write_u8(5);
+        vm.write_u8(0);
+        vm.write_u16(i16::MIN as u16);
+        vm.run().unwrap();
 
-    #[test]
-    fn test_gt() {
-        let mut vm = VM::new();
+        // Immediates are sign-extended
+        assert_eq!(vm.registers[1], 9);
+        assert_eq!(vm.registers[2], -2);
+        assert_eq!(vm.registers[3], 3000);
+        assert_eq!(vm.registers[4], 32777);
+        assert_eq!(vm.registers[5], 32778);
 
-        // Test greater than = true
-        vm.write_opcode(OpCode::LOAD);
+        let mut vm = VM::new();
+        vm.registers[0] = i64::MIN;
+        vm.write_opcode(OpCode::SUBI);
         vm.write_u8(0);
-        vm.write_u16(14);
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(1);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::GT);
         vm.write_u8(0);
-        vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, true);
+        vm.write_u16(1);
+        assert_eq!(vm.run(), Err(VmError::ArithmeticOverflow { pc: 0 }));
+    }
 
-        // Test greater than = false
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(0);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::GT);
+    // Compares %0 with an immediate value, returning the comparison flag
+    fn compare_immediate(opcode: OpCode, a: i64, b: i16) -> bool {
+        let mut vm = VM::new();
+        vm.registers[0] = a;
+        vm.write_opcode(opcode);
         vm.write_u8(0);
-        vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, false);
+        vm.write_u16(b as u16);
+        vm.run().unwrap();
+        vm.comparison
     }
 
     #[test]
-    fn test_lt() {
-        let mut vm = VM::new();
+    fn test_compare_immediate() {
+        assert!(compare_immediate(OpCode::EQI, 100, 100));
+        assert!(!compare_immediate(OpCode::EQI, 99, 100));
+        assert!(compare_immediate(OpCode::NEQI, 99, 100));
+        assert!(compare_immediate(OpCode::GTI, 101, 100));
+        assert!(!compare_immediate(OpCode::GTI, -1, 100));
+        assert!(compare_immediate(OpCode::LTI, -1, 100));
+        assert!(compare_immediate(OpCode::GTEI, 100, 100));
+        assert!(compare_immediate(OpCode::LTEI, 100, 100));
+        assert!(!compare_immediate(OpCode::LTEI, 101, 100));
 
-        // Test less than = true
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(0);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(1);
-        vm.write_u16(14);
-        vm.write_opcode(OpCode::LT);
-        vm.write_u8(0);
-        vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, true);
+        // Immediates are sign-extended
+        assert!(compare_immediate(OpCode::EQI, -1, -1));
+        assert!(compare_immediate(OpCode::LTI, -2, -1));
+        assert!(!compare_immediate(OpCode::LTI, 0, -1));
+        assert!(compare_immediate(OpCode::GTI, 32768, 32767));
+        assert!(compare_immediate(OpCode::LTEI, -32768, -32768));
+        assert!(!compare_immediate(OpCode::GTEI, -32769, -32768));
+    }
 
-        // Test less than = false
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(0);
-        vm.write_u16(15);
-        vm.write_opcode(OpCode::LT);
+    #[test]
+    fn test_loadw() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::LOADW);
         vm.write_u8(0);
+        vm.write_i64(i64::MIN);
+        vm.write_opcode(OpCode::LOADW);
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, false);
+        vm.write_i64(70000);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], i64::MIN);
+        assert_eq!(vm.registers[1], 70000);
+        assert_eq!(vm.pc, 20);
     }
 
     #[test]
-    fn test_gte() {
+    fn test_float_instructions() {
         let mut vm = VM::new();
-
-        // Test greater than or equal = true
-        // Greater than
-        vm.write_opcode(OpCode::LOAD);
+        vm.write_opcode(OpCode::FLOAD); // 0
         vm.write_u8(0);
-        vm.write_u16(14);
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(1);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::GTE);
+        vm.write_f64(1.5);
+        vm.write_opcode(OpCode::LOAD); // 10
         vm.write_u8(0);
+        vm.write_u16(4);
+        vm.write_opcode(OpCode::ITOF); // 14
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, true);
-
-        // Equal to
-        vm.write_opcode(OpCode::LOAD);
         vm.write_u8(0);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::GTE);
+        vm.write_opcode(OpCode::FMUL); // 17
+        vm.write_u8(2);
         vm.write_u8(0);
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, true);
-
-        // Test greater than or equal = false
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(0);
-        vm.write_u16(12);
-        vm.write_opcode(OpCode::GTE);
+        vm.write_opcode(OpCode::FSUB); // 21
+        vm.write_u8(3);
+        vm.write_u8(2);
         vm.write_u8(0);
+        vm.write_opcode(OpCode::FDIV); // 25
+        vm.write_u8(4);
+        vm.write_u8(3);
+        vm.write_u8(1);
+        vm.write_opcode(OpCode::FMOV); // 29
+        vm.write_u8(5);
+        vm.write_u8(4);
+        vm.write_opcode(OpCode::FTOI); // 32
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, false);
+        vm.write_u8(2);
+        vm.write_opcode(OpCode::FGT); // 35
+        vm.write_u8(2);
+        vm.write_u8(3);
+        vm.run().unwrap();
+
+        assert_eq!(vm.float_registers[1], 4.0);
+        assert_eq!(vm.float_registers[2], 6.0);
+        assert_eq!(vm.float_registers[3], 4.5);
+        assert_eq!(vm.float_registers[5], 1.125);
+        assert_eq!(vm.registers[1], 6);
+        assert!(vm.comparison);
     }
 
     #[test]
-    fn test_lte() {
+    fn test_float_edge_cases() {
         let mut vm = VM::new();
-
-        // Test less than or equal = true
-        // Less than
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(0);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::LOAD);
-        vm.write_u8(1);
-        vm.write_u16(14);
-        vm.write_opcode(OpCode::LTE);
-        vm.write_u8(0);
+        vm.float_registers[1] = 1.0;
+        vm.write_opcode(OpCode::FDIV); // 0
+        vm.write_u8(2);
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, true);
-
-        // Equal to
-        vm.write_opcode(OpCode::LOAD);
         vm.write_u8(0);
-        vm.write_u16(13);
-        vm.write_opcode(OpCode::LTE);
+        vm.write_opcode(OpCode::FDIV); // 4
+        vm.write_u8(3);
         vm.write_u8(0);
-        vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, true);
-
-        // Test less than or equal = false
-        vm.write_opcode(OpCode::LOAD);
         vm.write_u8(0);
-        vm.write_u16(15);
-        vm.write_opcode(OpCode::LTE);
+        vm.write_opcode(OpCode::FTOI); // 8
         vm.write_u8(0);
+        vm.write_u8(2);
+        vm.write_opcode(OpCode::FTOI); // 11
         vm.write_u8(1);
-        vm.run();
-        assert_eq!(vm.comparison, false);
+        vm.write_u8(3);
+        vm.write_opcode(OpCode::FEQ); // 14
+        vm.write_u8(3);
+        vm.write_u8(3);
+        vm.run().unwrap();
+
+        // Division by zero is not an error for floats
+        assert_eq!(vm.float_registers[2], f64::INFINITY);
+        assert!(vm.float_registers[3].is_nan());
+        assert_eq!(vm.registers[0], i64::MAX);
+        assert_eq!(vm.registers[1], 0);
+        assert!(!vm.comparison);
     }
 
     #[test]
-    fn test_jeq() {
+    fn test_trap_handler() {
         let mut vm = VM::new();
-
         vm.write_opcode(OpCode::LOAD); // 0
         vm.write_u8(0); // 1
-        vm.write_u16(13); // 2, 3
-        vm.write_opcode(OpCode::LOAD); // 4
-        vm.write_u8(1); // 5
-        vm.write_u16(13); // 6, 7
+        vm.write_u16(19); // 2
+        vm.write_opcode(OpCode::TRAP); // 4
+        vm.write_u16(TrapKind::DivisionByZero.code()); // 5
+        vm.write_u8(0); // 7
         vm.write_opcode(OpCode::LOAD); // 8
-        vm.write_u8(2); // 9
-        vm.write_u16(18); // 10, 11
-        vm.write_opcode(OpCode::EQ); // 12
-        vm.write_u8(0); // 13
+        vm.write_u8(1); // 9
+        vm.write_u16(10); // 10
+        vm.write_opcode(OpCode::DIV); // 12
+        vm.write_u8(2); // 13
         vm.write_u8(1); // 14
-        vm.write_opcode(OpCode::JEQ); // 15
-        vm.write_u8(2); // 16
+        vm.write_u8(3); // 15
+        vm.write_opcode(OpCode::STOP); // 16
         vm.write_opcode(OpCode::STOP); // 17
-        vm.write_opcode(OpCode::LOAD); // 18
-        vm.write_u8(3); // 19
-        vm.write_u16(14); // 20, 21
-        vm.run();
+        vm.write_opcode(OpCode::STOP); // 18
+        vm.write_opcode(OpCode::LOAD); // 19
+        vm.write_u8(2); // 20
+        vm.write_u16(7); // 21
 
-        assert_eq!(vm.comparison, true);
-        assert_eq!(vm.registers[3], 14); // Should have jumped to 18 and skipped over the stop instruction
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], 7);
+        assert_eq!(
+            vm.registers[TRAP_CAUSE_REGISTER as usize],
+            TrapKind::DivisionByZero.code() as i64
+        );
+        assert_eq!(vm.registers[TRAP_PC_REGISTER as usize], 12);
+        assert_eq!(vm.trap_handler(TrapKind::DivisionByZero), Some(19));
     }
 
     #[test]
-    fn test_jne() {
+    fn test_unhandled_trap() {
         let mut vm = VM::new();
+        vm.set_trap_handler(TrapKind::UnknownSyscall, 0);
+        vm.write_opcode(OpCode::UNTRAP); // 0
+        vm.write_u16(TrapKind::UnknownSyscall.code()); // 1
+        vm.write_opcode(OpCode::SYS); // 3
+        vm.write_u16(1); // 4
 
-        vm.write_opcode(OpCode::LOAD); // 0
+        // Handlers for other kinds do not apply
+        vm.set_trap_handler(TrapKind::DivisionByZero, 0);
+        assert_eq!(vm.run(), Err(VmError::UnknownSyscall { id: 1, pc: 3 }));
+        assert_eq!(vm.trap_handler(TrapKind::UnknownSyscall), None);
+
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::UNTRAP);
+        vm.write_u16(0);
+        assert_eq!(vm.run(), Err(VmError::InvalidTrapKind { pc: 0, kind: 0 }));
+    }
+
+    #[test]
+    fn test_trap_unknown_opcode() {
+        let mut vm = VM::new();
+        vm.set_trap_handler(TrapKind::UnknownOpcode, 2);
+        vm.write_u8(255); // 0
+        vm.write_opcode(OpCode::STOP); // 1
+        vm.write_opcode(OpCode::INC); // 2
+        vm.write_u8(0); // 3
+
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(
+            vm.registers[TRAP_CAUSE_REGISTER as usize],
+            TrapKind::UnknownOpcode.code() as i64
+        );
+        assert_eq!(vm.registers[TRAP_PC_REGISTER as usize], 0);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::INC); // 0
         vm.write_u8(0); // 1
-        vm.write_u16(13); // 2, 3
-        vm.write_opcode(OpCode::LOAD); // 4
-        vm.write_u8(1); // 5
-        vm.write_u16(13); // 6, 7
-        vm.write_opcode(OpCode::LOAD); // 8
-        vm.write_u8(2); // 9
-        vm.write_u16(18); // 10, 11
-        vm.write_opcode(OpCode::EQ); // 12
-        vm.write_u8(0); // 13
-        vm.write_u8(1); // 14
-        vm.write_opcode(OpCode::JNE); // 15
-        vm.write_u8(2); // 16
-        vm.write_opcode(OpCode::STOP); // 17
-        vm.write_opcode(OpCode::LOAD); // 18
-        vm.write_u8(3); // 19
-        vm.write_u16(14); // 20, 21
-        vm.run();
+        vm.write_opcode(OpCode::EQI); // 2
+        vm.write_u8(0); // 3
+        vm.write_u16(1); // 4
+        vm.write_opcode(OpCode::STOP); // 6
+        vm.write_opcode(OpCode::INC); // 7
+        vm.write_u8(1); // 8
+        vm.write_opcode(OpCode::RETI); // 9
+        vm.set_interrupt_vector(3, 7);
 
-        assert_eq!(vm.comparison, true);
-        assert_ne!(vm.registers[3], 14); // Should have jumped to 14 and executed the stop instruction, never loading 14 into register 3
+        vm.queue_interrupt(3);
+        assert!(vm.has_pending_interrupts());
+        assert_eq!(vm.step(), ExecutionState::Running);
+        assert!(!vm.has_pending_interrupts());
+        assert!(!vm.interrupts_enabled());
+        assert_eq!(
+            vm.call_frames(),
+            &[CallFrame {
+                call_site: 0,
+                target: 7,
+                return_address: 0,
+                interrupt: Some(3)
+            }]
+        );
+
+        assert_eq!(vm.run(), Ok(HaltReason::Stopped));
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 1);
+        assert!(vm.interrupts_enabled());
+        assert!(vm.call_frames().is_empty());
     }
 
     #[test]
-    fn test_sys() {
+    fn test_interrupt_at_end_of_code() {
         let mut vm = VM::new();
+        vm.write_opcode(OpCode::INC); // 0
+        vm.write_u8(1); // 1
+        vm.write_opcode(OpCode::RETI); // 2
+        vm.set_interrupt_vector(0, 0);
+        vm.pc = 3;
 
-        vm.register_syscall(0, |vm| {
-            vm.registers[0] = 321;
+        vm.queue_interrupt(0);
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.pc, 3);
+    }
 
-            true
-        });
+    #[test]
+    fn test_interrupt_vector_out_of_bounds() {
+        for fuel in [None, Some(10)] {
+            let mut vm = VM::new();
+            vm.write_opcode(OpCode::INC); // 0
+            vm.write_u8(0); // 1
+            vm.set_interrupt_vector(0, 100);
 
-        vm.write_opcode(OpCode::SYS);
-        vm.write_u16(0);
-        vm.run();
+            vm.queue_interrupt(0);
+            let result = match fuel {
+                Some(fuel) => vm.run_with_fuel(fuel),
+                None => vm.run(),
+            };
+            assert_eq!(result, Err(VmError::JumpOutOfBounds { pc: 0, target: 100 }));
+            assert_eq!(vm.pc, 0);
+            assert!(vm.call_frames().is_empty());
 
-        assert_eq!(vm.registers[0], 321);
+            // The interrupt is consumed, so the VM can be resumed
+            assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+            assert_eq!(vm.registers[0], 1);
+        }
+    }
+
+    #[test]
+    fn test_interrupt_restores_flags() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::EQI); // 0
+        vm.write_u8(0); // 1
+        vm.write_u16(0); // 2
+        vm.write_opcode(OpCode::STOP); // 4
+        vm.write_opcode(OpCode::NEQI); // 5
+        vm.write_u8(0); // 6
+        vm.write_u16(0); // 7
+        vm.write_opcode(OpCode::RETI); // 9
+        vm.set_interrupt_vector(0, 5);
+
+        vm.step();
+        vm.queue_interrupt(0);
+        assert_eq!(vm.run(), Ok(HaltReason::Stopped));
+        assert!(vm.comparison);
+        assert!(vm.flags.zero);
+    }
+
+    #[test]
+    fn test_repeated_interrupts_are_coalesced() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::INC); // 0
+        vm.write_u8(1); // 1
+        vm.write_opcode(OpCode::RETI); // 2
+        vm.set_interrupt_vector(7, 0);
+        vm.pc = 3;
+
+        vm.set_interrupts_enabled(false);
+        for _ in 0..1000 {
+            vm.queue_interrupt(7);
+        }
+        assert_eq!(vm.pending_interrupts().collect::<Vec<_>>(), vec![7]);
+
+        vm.set_interrupts_enabled(true);
+        assert_eq!(vm.run(), Ok(HaltReason::EndOfCode));
+        assert_eq!(vm.registers[1], 1);
+        assert!(!vm.has_pending_interrupts());
+    }
+
+    #[test]
+    fn test_disabled_interrupts() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::DI); // 0
+        vm.write_opcode(OpCode::INC); // 1
+        vm.write_u8(0); // 2
+        vm.write_opcode(OpCode::EI); // 3
+        vm.write_opcode(OpCode::STOP); // 4
+        vm.write_opcode(OpCode::MOV); // 5
+        vm.write_u8(1); // 6
+        vm.write_u8(0); // 7
+        vm.write_opcode(OpCode::RETI); // 8
+        vm.set_interrupt_vector(1, 5);
+
+        vm.step();
+        vm.queue_interrupt(2);
+        vm.queue_interrupt(1);
+        vm.step();
+        assert_eq!(vm.pending_interrupts().collect::<Vec<_>>(), vec![1, 2]);
+
+        // Interrupt 2 has no vector and is discarded
+        assert_eq!(vm.run(), Ok(HaltReason::Stopped));
+        assert_eq!(vm.registers[1], 1);
+        assert!(!vm.has_pending_interrupts());
+    }
+
+    #[test]
+    fn test_invalid_return() {
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::RETI);
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow { pc: 0 }));
+
+        let mut vm = VM::new();
+        vm.write_opcode(OpCode::RET); // 0
+        vm.set_interrupt_vector(0, 0);
+        vm.queue_interrupt(0);
+        assert_eq!(vm.run(), Err(VmError::InvalidReturn { pc: 0 }));
     }
 }